@@ -0,0 +1,111 @@
+use anyhow::{bail, Result};
+
+/// Set of piece indices packed as in the BitTorrent `bitfield` message:
+/// the high bit of the first byte is piece 0
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Parses a bitfield of `len` pieces received from a peer, rejecting a wrong
+    /// size or spare bits set past the last piece
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self> {
+        let mut bitfield = Self::new(len);
+        if bytes.len() != bitfield.bytes.len() {
+            bail!(
+                "Bitfield has {} bytes, expected {}",
+                bytes.len(),
+                bitfield.bytes.len()
+            );
+        }
+        bitfield.bytes.copy_from_slice(bytes);
+        if bitfield
+            .bytes
+            .last()
+            .is_some_and(|last| last & !bitfield.last_byte_mask() != 0)
+        {
+            bail!("Bitfield has spare bits set");
+        }
+        Ok(bitfield)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Bits of the last byte that map to actual pieces
+    fn last_byte_mask(&self) -> u8 {
+        match self.len % 8 {
+            0 => 0xff,
+            used => 0xff << (8 - used),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Bitfield;
+
+    #[test]
+    fn it_sets_bits_high_bit_first() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0);
+        bitfield.set(9);
+        bitfield.set(10);
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield.count(), 2);
+
+        let bitfield = Bitfield::from_bytes(&[0xff, 0b1100_0000], 10).unwrap();
+        assert_eq!(bitfield.count(), 10);
+    }
+
+    #[test]
+    fn it_rejects_malformed_bitfields() {
+        assert!(Bitfield::from_bytes(&[0xff], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff, 0, 0], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff, 0b1110_0000], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff], 8).is_ok());
+    }
+}
@@ -0,0 +1,1167 @@
+use anyhow::{anyhow, bail, Result};
+use futures::future::join_all;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::tracker::Peer;
+
+pub type NodeId = [u8; 20];
+
+/// Nodes per bucket, and nodes kept as the result of a lookup
+const K: usize = 8;
+/// Queries running at once during a lookup
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Unanswered queries after which a node is replaced by any new one
+const MAX_FAILURES: u32 = 2;
+/// Time after which a silent node is pinged and an unchanged bucket refreshed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How often tokens rotate, a token stays valid for two rotations
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// How long an announced peer is returned to `get_peers` queries
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_TORRENT: usize = 200;
+/// Info-hashes peers are stored for, so that announces for random ones cannot use up memory
+const MAX_TORRENTS: usize = 2000;
+/// How often the routing table, tokens and stored peers are maintained
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// How often a torrent is looked up and announced again
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const MAX_PACKET_SIZE: usize = 1500;
+/// Different nodes that must report the same external IP before our id is derived from it
+const MIN_IP_VOTES: usize = 4;
+/// How long a node's report of our external IP is counted
+const IP_VOTE_TTL: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone)]
+pub struct DhtSettings {
+    /// UDP port the node listens on
+    pub port: u16,
+    /// Well known nodes used to join the network, as `host:port`
+    pub routers: Vec<String>,
+    /// Where the node id and routing table are kept between runs
+    pub state_path: PathBuf,
+}
+
+impl Default for DhtSettings {
+    fn default() -> Self {
+        Self {
+            port: 6881,
+            routers: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+            ],
+            state_path: PathBuf::from("furia.dht"),
+        }
+    }
+}
+
+/// A KRPC message: a query, a response or an error
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Message {
+    t: ByteBuf,
+    y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<Arguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<Response>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+    /// Compact address the response was sent to, telling the querying node its external IP (BEP 42)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<ByteBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Arguments {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Response {
+    id: ByteBuf,
+    /// Compact node infos, 26 bytes each
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    /// Compact peer infos, 6 bytes each
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+fn node_id(bytes: &[u8]) -> Result<NodeId> {
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("Node id of {} bytes", bytes.len()))
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+pub(crate) fn encode_peer(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut bytes = addr.ip().octets().to_vec();
+    bytes.extend(addr.port().to_be_bytes());
+    bytes
+}
+
+pub(crate) fn decode_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    let bytes: [u8; 6] = bytes.try_into().ok()?;
+    Some(SocketAddrV4::new(
+        Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    nodes
+        .iter()
+        .flat_map(|node| [node.id.to_vec(), encode_peer(&node.addr)].concat())
+        .collect()
+}
+
+fn decode_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes
+        .chunks_exact(26)
+        .filter_map(|chunk| {
+            Some(NodeInfo {
+                id: node_id(&chunk[..20]).ok()?,
+                addr: decode_peer(&chunk[20..])?,
+            })
+        })
+        .collect()
+}
+
+/// Castagnoli CRC-32, used to derive secure node ids
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Addresses exempt from BEP 42, which can't be tied to a node id
+fn is_local(ip: &Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+}
+
+/// First 21 bits of a secure node id for `ip`, from the 3 random bits in `seed`
+fn secure_prefix(ip: &Ipv4Addr, seed: u8) -> u32 {
+    let masked = u32::from(*ip) & 0x030f_3fff | ((seed as u32 & 7) << 29);
+    crc32c(&masked.to_be_bytes())
+}
+
+/// A node id tied to our external IP as BEP 42 requires, with `seed` as its last byte
+pub fn secure_node_id(ip: &Ipv4Addr, seed: u8) -> NodeId {
+    let mut id: NodeId = rand::thread_rng().gen();
+    let crc = secure_prefix(ip, seed);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 7);
+    id[19] = seed;
+    id
+}
+
+/// Whether a node id was derived from `ip`, always true for local addresses
+pub fn is_secure_id(id: &NodeId, ip: &Ipv4Addr) -> bool {
+    if is_local(ip) {
+        return true;
+    }
+    let crc = secure_prefix(ip, id[19]);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+/// Node id, external IP and known nodes saved between runs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DhtState {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<ByteBuf>,
+    /// Compact node infos of the routing table
+    nodes: ByteBuf,
+}
+
+impl DhtState {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let bytes = tokio::fs::read(path).await?;
+        Ok(serde_bencode::from_bytes(&bytes)?)
+    }
+
+    /// Writes the state through a temporary file, so a crash never leaves a truncated one
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let bytes = serde_bencode::to_bytes(self)?;
+        let temporary = path.with_extension("tmp");
+        tokio::fs::write(&temporary, bytes).await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+
+    /// Saved nodes, as `ip:port` to bootstrap from
+    pub fn nodes(&self) -> Vec<String> {
+        decode_nodes(&self.nodes)
+            .iter()
+            .map(|node| node.addr.to_string())
+            .collect()
+    }
+
+    fn external_ip(&self) -> Option<Ipv4Addr> {
+        let octets: [u8; 4] = self.ip.as_deref()?.as_slice().try_into().ok()?;
+        Some(Ipv4Addr::from(octets))
+    }
+}
+
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+struct Bucket {
+    entries: Vec<Entry>,
+    last_changed: Instant,
+}
+
+/// Known nodes, in 160 buckets by the length of the prefix they share with our id
+pub(crate) struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub(crate) fn new(id: NodeId) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            buckets: (0..160)
+                .map(|_| Bucket {
+                    entries: Vec::new(),
+                    last_changed: now,
+                })
+                .collect(),
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let zeros = distance
+            .iter()
+            .position(|byte| *byte != 0)
+            .map(|index| index * 8 + distance[index].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    /// Records that a node answered or queried us. A full bucket only takes the
+    /// node in place of one that stopped answering.
+    pub(crate) fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return false;
+        };
+        let now = Instant::now();
+        let bucket = &mut self.buckets[index];
+        if let Some(entry) = bucket.entries.iter_mut().find(|e| e.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = now;
+            entry.failures = 0;
+            return true;
+        }
+        let entry = Entry {
+            node,
+            last_seen: now,
+            failures: 0,
+        };
+        if bucket.entries.len() < K {
+            bucket.entries.push(entry);
+        } else if let Some(bad) = bucket
+            .entries
+            .iter_mut()
+            .find(|e| e.failures >= MAX_FAILURES)
+        {
+            *bad = entry;
+        } else {
+            return false;
+        }
+        bucket.last_changed = now;
+        true
+    }
+
+    fn failed(&mut self, addr: &SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            for entry in bucket.entries.iter_mut().filter(|e| e.node.addr == *addr) {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// The `count` known nodes closest to `target`
+    pub(crate) fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| &bucket.entries)
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .map(|entry| entry.node.clone())
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.entries.len()).sum()
+    }
+
+    /// Nodes not heard from for a while, to ping
+    fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| &bucket.entries)
+            .filter(|entry| now.duration_since(entry.last_seen) > REFRESH_INTERVAL)
+            .map(|entry| entry.node.clone())
+            .collect()
+    }
+
+    /// Random ids in buckets that did not change for a while, up to the deepest used bucket
+    fn refresh_targets(&self, now: Instant) -> Vec<NodeId> {
+        let deepest = self
+            .buckets
+            .iter()
+            .rposition(|bucket| !bucket.entries.is_empty())
+            .unwrap_or(0);
+        (0..=deepest)
+            .filter(|&index| {
+                now.duration_since(self.buckets[index].last_changed) > REFRESH_INTERVAL
+            })
+            .map(|index| {
+                let mut id: NodeId = rand::thread_rng().gen();
+                for bit in 0..=index {
+                    let mask = 0x80 >> (bit % 8);
+                    let own = self.id[bit / 8] & mask;
+                    let wanted = if bit == index { own ^ mask } else { own };
+                    id[bit / 8] = (id[bit / 8] & !mask) | wanted;
+                }
+                id
+            })
+            .collect()
+    }
+}
+
+/// Secrets of the tokens handed out by `get_peers` and checked by `announce_peer`
+struct Tokens {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn token(secret: &[u8; 16], ip: &Ipv4Addr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(ip.octets());
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn is_valid(&self, token: &[u8], ip: &Ipv4Addr) -> bool {
+        token == Self::token(&self.current, ip) || token == Self::token(&self.previous, ip)
+    }
+}
+
+struct State {
+    table: RoutingTable,
+    /// Queries waiting for their response, by transaction id, with the node they were sent to
+    pending: HashMap<Vec<u8>, (SocketAddrV4, oneshot::Sender<Result<Response>>)>,
+    next_transaction: u16,
+    tokens: Tokens,
+    /// Peers announced to us, by info-hash
+    peers: HashMap<NodeId, Vec<(SocketAddrV4, Instant)>>,
+    /// Our IP as seen by each node that answered us, with when it last told us
+    external_ip_votes: HashMap<SocketAddrV4, (Ipv4Addr, Instant)>,
+    /// Our IP saved by a previous run, until nodes report one
+    saved_external_ip: Option<Ipv4Addr>,
+}
+
+impl State {
+    /// The IP most nodes see us at, with the number of nodes seeing it
+    fn tally_external_ip(&self) -> Option<(Ipv4Addr, usize)> {
+        let mut votes = HashMap::<Ipv4Addr, usize>::new();
+        for (ip, _) in self.external_ip_votes.values() {
+            *votes.entry(*ip).or_default() += 1;
+        }
+        votes.into_iter().max_by_key(|(_, votes)| *votes)
+    }
+
+    fn external_ip(&self) -> Option<Ipv4Addr> {
+        self.tally_external_ip()
+            .map(|(ip, _)| ip)
+            .or(self.saved_external_ip)
+    }
+
+    /// Records a node's view of our IP, one vote per node. Once enough nodes agree on a
+    /// public IP our id does not match, a secure id is derived from it and the routing
+    /// table rebuilt around it.
+    fn vote_external_ip(&mut self, voter: SocketAddrV4, ip: Ipv4Addr) {
+        self.external_ip_votes.insert(voter, (ip, Instant::now()));
+        let Some((ip, votes)) = self.tally_external_ip() else {
+            return;
+        };
+        if votes < MIN_IP_VOTES || is_secure_id(&self.table.id, &ip) {
+            return;
+        }
+        let id = secure_node_id(&ip, rand::thread_rng().gen());
+        info!(%ip, "Changing the DHT node id to match the external IP");
+        let nodes = self.table.closest(&self.table.id, self.table.len());
+        self.table = RoutingTable::new(id);
+        for node in nodes {
+            self.table.insert(node);
+        }
+    }
+}
+
+struct Inner {
+    socket: Arc<UdpSocket>,
+    state: Mutex<State>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    /// Keep nodes whose id does not match their IP out of the routing table
+    enforce_secure_ids: AtomicBool,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// A node of the mainline DHT (BEP 5). Clones share the same node.
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+/// What an iterative `get_peers` lookup found
+struct Lookup {
+    peers: Vec<SocketAddrV4>,
+    /// Closest nodes that answered, with the token to announce to them
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+}
+
+impl Dht {
+    /// Starts a node with a random id on `address`
+    pub async fn bind(address: SocketAddr) -> Result<Self> {
+        Self::with_id(address, rand::thread_rng().gen()).await
+    }
+
+    pub async fn with_id(address: SocketAddr, id: NodeId) -> Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        let mut rng = rand::thread_rng();
+        let inner = Arc::new(Inner {
+            socket: socket.clone(),
+            state: Mutex::new(State {
+                table: RoutingTable::new(id),
+                pending: HashMap::new(),
+                next_transaction: rng.gen(),
+                tokens: Tokens {
+                    current: rng.gen(),
+                    previous: rng.gen(),
+                    rotated: Instant::now(),
+                },
+                peers: HashMap::new(),
+                external_ip_votes: HashMap::new(),
+                saved_external_ip: None,
+            }),
+            tasks: Mutex::new(Vec::new()),
+            enforce_secure_ids: AtomicBool::new(true),
+        });
+        let receive = tokio::spawn(receive(socket, Arc::downgrade(&inner)));
+        let maintain = tokio::spawn(maintain(Arc::downgrade(&inner)));
+        inner.tasks.lock().unwrap().extend([receive, maintain]);
+        Ok(Self { inner })
+    }
+
+    /// Starts a node with the id of a previous run, or a new one if that id does not
+    /// match the external IP seen back then. Bootstrap from [`DhtState::nodes`] afterwards.
+    pub async fn restore(address: SocketAddr, state: Option<&DhtState>) -> Result<Self> {
+        let saved_id = state.and_then(|state| node_id(&state.id).ok());
+        let external_ip = state.and_then(DhtState::external_ip);
+        let id = match (saved_id, external_ip) {
+            (Some(id), Some(ip)) if is_secure_id(&id, &ip) => id,
+            (_, Some(ip)) => secure_node_id(&ip, rand::thread_rng().gen()),
+            (Some(id), None) => id,
+            (None, None) => rand::thread_rng().gen(),
+        };
+        let dht = Self::with_id(address, id).await?;
+        dht.lock().saved_external_ip = external_ip;
+        Ok(dht)
+    }
+
+    /// Node id, external IP and routing table, to restore the node in a later run
+    pub fn save_state(&self) -> DhtState {
+        let state = self.lock();
+        let ip = state.external_ip();
+        let nodes = state.table.closest(&state.table.id, state.table.len());
+        DhtState {
+            id: ByteBuf::from(state.table.id.to_vec()),
+            ip: ip.map(|ip| ByteBuf::from(ip.octets().to_vec())),
+            nodes: ByteBuf::from(encode_nodes(&nodes)),
+        }
+    }
+
+    /// Only admit nodes whose id matches their IP to the routing table (BEP 42), the default
+    pub fn enforce_secure_ids(&self, enforce: bool) {
+        self.inner
+            .enforce_secure_ids
+            .store(enforce, Ordering::Relaxed);
+    }
+
+    /// Our id, which changes once the external IP is known if it does not match it
+    pub fn id(&self) -> NodeId {
+        self.lock().table.id
+    }
+
+    /// Our IP as most nodes see it
+    pub fn external_ip(&self) -> Option<Ipv4Addr> {
+        self.lock().external_ip()
+    }
+
+    fn add_node(&self, state: &mut State, node: NodeInfo) {
+        if self.inner.enforce_secure_ids.load(Ordering::Relaxed)
+            && !is_secure_id(&node.id, node.addr.ip())
+        {
+            debug!(addr = %node.addr, "Ignoring node with an insecure id");
+            return;
+        }
+        state.table.insert(node);
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Number of nodes in the routing table
+    pub fn node_count(&self) -> usize {
+        self.lock().table.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap()
+    }
+
+    /// Joins the network through `nodes`, given as `host:port`, then looks up our own id
+    /// to fill the routing table. Returns the number of nodes known afterwards.
+    pub async fn bootstrap(&self, nodes: &[String]) -> usize {
+        let mut addresses = Vec::new();
+        for node in nodes {
+            match lookup_host(node.as_str()).await {
+                Ok(resolved) => addresses.extend(resolved.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => debug!(?e, node, "Failed to resolve DHT node"),
+            }
+        }
+        let own_id = self.id();
+        join_all(addresses.iter().map(|addr| self.find_node(*addr, own_id))).await;
+        self.lookup(own_id, false).await;
+        let count = self.node_count();
+        info!(count, "DHT bootstrapped");
+        count
+    }
+
+    pub async fn ping(&self, addr: SocketAddrV4) -> Result<NodeId> {
+        let response = self.query(addr, "ping", Arguments::default()).await?;
+        node_id(&response.id)
+    }
+
+    /// Asks a node for the nodes it knows closest to `target`
+    pub async fn find_node(&self, addr: SocketAddrV4, target: NodeId) -> Result<Vec<NodeInfo>> {
+        let arguments = Arguments {
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        };
+        let response = self.query(addr, "find_node", arguments).await?;
+        Ok(decode_nodes(
+            response.nodes.as_deref().map_or(&[][..], |nodes| nodes),
+        ))
+    }
+
+    /// Finds peers of a torrent through the nodes closest to its info-hash
+    pub async fn get_peers(&self, info_hash: NodeId) -> Vec<SocketAddrV4> {
+        self.lookup(info_hash, true).await.peers
+    }
+
+    /// Finds peers of a torrent and tells the closest nodes that we download it on `port`
+    pub async fn announce(&self, info_hash: NodeId, port: u16) -> Vec<SocketAddrV4> {
+        let lookup = self.lookup(info_hash, true).await;
+        let announces = lookup.closest.into_iter().filter_map(|(node, token)| {
+            let arguments = Arguments {
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                port: Some(port as i64),
+                token: Some(ByteBuf::from(token?)),
+                ..Default::default()
+            };
+            Some(self.query(node.addr, "announce_peer", arguments))
+        });
+        let announced = join_all(announces)
+            .await
+            .into_iter()
+            .filter(Result::is_ok)
+            .count();
+        debug!(announced, "Announced to DHT nodes");
+        lookup.peers
+    }
+
+    /// Looks up and announces a torrent every `ANNOUNCE_INTERVAL`, sending the peers
+    /// found to `peers` until it closes
+    pub async fn find_peers(
+        self,
+        info_hash: NodeId,
+        port: u16,
+        peers: mpsc::UnboundedSender<Peer>,
+    ) {
+        loop {
+            let found = self.announce(info_hash, port).await;
+            info!(count = found.len(), "Peers found on the DHT");
+            for addr in found {
+                let peer = Peer {
+                    peer_id: None,
+                    ip: addr.ip().to_string(),
+                    port: addr.port() as i64,
+                };
+                if peers.send(peer).is_err() {
+                    return;
+                }
+            }
+            tokio::select! {
+                _ = peers.closed() => return,
+                _ = time::sleep(ANNOUNCE_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Iterative lookup of the nodes closest to `target`, querying `get_peers` or `find_node`
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let own_id = self.id();
+        let mut shortlist = self
+            .lock()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect::<BTreeMap<_, _>>();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut peers = HashSet::new();
+        loop {
+            let candidates = shortlist
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .cloned()
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
+                break;
+            }
+            let queries = candidates.iter().map(|node| {
+                queried.insert(node.addr);
+                let arguments = if get_peers {
+                    Arguments {
+                        info_hash: Some(ByteBuf::from(target.to_vec())),
+                        ..Default::default()
+                    }
+                } else {
+                    Arguments {
+                        target: Some(ByteBuf::from(target.to_vec())),
+                        ..Default::default()
+                    }
+                };
+                let method = if get_peers { "get_peers" } else { "find_node" };
+                self.query(node.addr, method, arguments)
+            });
+            let responses = join_all(queries).await;
+            for (node, response) in candidates.into_iter().zip(responses) {
+                let key = distance(&node.id, &target);
+                let Ok(response) = response else {
+                    shortlist.remove(&key);
+                    continue;
+                };
+                for found in decode_nodes(response.nodes.as_deref().map_or(&[][..], |nodes| nodes))
+                {
+                    if found.id != own_id {
+                        shortlist
+                            .entry(distance(&found.id, &target))
+                            .or_insert(found);
+                    }
+                }
+                peers.extend(
+                    response
+                        .values
+                        .iter()
+                        .flatten()
+                        .filter_map(|v| decode_peer(v)),
+                );
+                responded.insert(key, (node, response.token.map(|t| t.into_vec())));
+            }
+        }
+        Lookup {
+            peers: peers.into_iter().collect(),
+            closest: responded.into_values().take(K).collect(),
+        }
+    }
+
+    /// Sends a query and waits for its response. Nodes that answer join the routing table.
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        mut arguments: Arguments,
+    ) -> Result<Response> {
+        arguments.id = ByteBuf::from(self.id().to_vec());
+        let (reply, reply_rx) = oneshot::channel();
+        let transaction = {
+            let mut state = self.lock();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            let transaction = state.next_transaction.to_be_bytes().to_vec();
+            state.pending.insert(transaction.clone(), (addr, reply));
+            transaction
+        };
+        let message = Message {
+            t: ByteBuf::from(transaction.clone()),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(arguments),
+            ..Default::default()
+        };
+        self.inner
+            .socket
+            .send_to(&serde_bencode::to_bytes(&message)?, addr)
+            .await?;
+        let response = match time::timeout(QUERY_TIMEOUT, reply_rx).await {
+            Ok(Ok(response)) => response,
+            _ => {
+                let mut state = self.lock();
+                state.pending.remove(&transaction);
+                state.table.failed(&addr);
+                bail!("No answer from {} to {}", addr, method);
+            }
+        }?;
+        let id = node_id(&response.id)?;
+        self.add_node(&mut self.lock(), NodeInfo { id, addr });
+        Ok(response)
+    }
+
+    /// Answers a query from another node
+    fn answer(
+        &self,
+        method: &str,
+        arguments: &Arguments,
+        from: SocketAddrV4,
+    ) -> Result<Response, (i64, String)> {
+        let mut state = self.lock();
+        if let Ok(id) = node_id(&arguments.id) {
+            self.add_node(&mut state, NodeInfo { id, addr: from });
+        }
+        let mut response = Response {
+            id: ByteBuf::from(state.table.id.to_vec()),
+            ..Default::default()
+        };
+        let protocol_error = || (203, "Protocol Error".to_string());
+        match method {
+            "ping" => {}
+            "find_node" => {
+                let target = arguments.target.as_deref().ok_or_else(protocol_error)?;
+                let target = node_id(target).map_err(|_| protocol_error())?;
+                response.nodes = Some(ByteBuf::from(encode_nodes(
+                    &state.table.closest(&target, K),
+                )));
+            }
+            "get_peers" => {
+                let info_hash = arguments.info_hash.as_deref().ok_or_else(protocol_error)?;
+                let info_hash = node_id(info_hash).map_err(|_| protocol_error())?;
+                response.token = Some(ByteBuf::from(Tokens::token(
+                    &state.tokens.current,
+                    from.ip(),
+                )));
+                match state.peers.get(&info_hash) {
+                    Some(peers) if !peers.is_empty() => {
+                        response.values = Some(
+                            peers
+                                .iter()
+                                .map(|(addr, _)| ByteBuf::from(encode_peer(addr)))
+                                .collect(),
+                        );
+                    }
+                    _ => {
+                        let nodes = state.table.closest(&info_hash, K);
+                        response.nodes = Some(ByteBuf::from(encode_nodes(&nodes)));
+                    }
+                }
+            }
+            "announce_peer" => {
+                let info_hash = arguments.info_hash.as_deref().ok_or_else(protocol_error)?;
+                let info_hash = node_id(info_hash).map_err(|_| protocol_error())?;
+                let token = arguments.token.as_deref().ok_or_else(protocol_error)?;
+                if !state.tokens.is_valid(token, from.ip()) {
+                    return Err((203, "Bad token".to_string()));
+                }
+                let port = match (arguments.implied_port, arguments.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => u16::try_from(port).map_err(|_| protocol_error())?,
+                    _ => return Err(protocol_error()),
+                };
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                if !state.peers.contains_key(&info_hash) && state.peers.len() >= MAX_TORRENTS {
+                    // Forget the torrent announced the longest ago
+                    let oldest = state
+                        .peers
+                        .iter()
+                        .min_by_key(|(_, peers)| peers.last().map(|(_, announced)| *announced))
+                        .map(|(info_hash, _)| *info_hash);
+                    if let Some(oldest) = oldest {
+                        state.peers.remove(&oldest);
+                    }
+                }
+                let peers = state.peers.entry(info_hash).or_default();
+                peers.retain(|(addr, _)| *addr != peer);
+                if peers.len() >= MAX_PEERS_PER_TORRENT {
+                    peers.remove(0);
+                }
+                peers.push((peer, Instant::now()));
+            }
+            _ => return Err((204, "Method Unknown".to_string())),
+        }
+        Ok(response)
+    }
+
+    async fn handle_packet(&self, packet: &[u8], from: SocketAddrV4) -> Result<()> {
+        let message: Message = serde_bencode::from_bytes(packet)?;
+        match message.y.as_str() {
+            "q" => {
+                let (Some(method), Some(arguments)) = (&message.q, &message.a) else {
+                    bail!("Query without method or arguments");
+                };
+                let mut reply = Message {
+                    t: message.t,
+                    ip: Some(ByteBuf::from(encode_peer(&from))),
+                    ..Default::default()
+                };
+                match self.answer(method, arguments, from) {
+                    Ok(response) => {
+                        reply.y = "r".to_string();
+                        reply.r = Some(response);
+                    }
+                    Err(error) => {
+                        reply.y = "e".to_string();
+                        reply.e = Some(error);
+                    }
+                }
+                self.inner
+                    .socket
+                    .send_to(&serde_bencode::to_bytes(&reply)?, from)
+                    .await?;
+            }
+            "r" | "e" => {
+                let mut state = self.lock();
+                // Only the queried node may answer, others could guess the transaction id
+                let pending = match state.pending.remove(message.t.as_slice()) {
+                    Some((addr, pending)) if addr == from => pending,
+                    Some(other) => {
+                        state.pending.insert(message.t.to_vec(), other);
+                        bail!("Response from {} to a query sent elsewhere", from);
+                    }
+                    None => bail!("Response to an unknown transaction"),
+                };
+                if let Some(ip) = message.ip.as_deref().and_then(|ip| decode_peer(ip)) {
+                    state.vote_external_ip(from, *ip.ip());
+                }
+                drop(state);
+                let result = match (message.r, message.e) {
+                    (Some(response), _) => Ok(response),
+                    (_, Some((code, text))) => Err(anyhow!("DHT error {}: {}", code, text)),
+                    _ => Err(anyhow!("Empty response")),
+                };
+                let _ = pending.send(result);
+            }
+            y => bail!("Unknown message type {}", y),
+        }
+        Ok(())
+    }
+}
+
+async fn receive(socket: Arc<UdpSocket>, inner: Weak<Inner>) {
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!(?e, "DHT socket failed");
+                continue;
+            }
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let dht = Dht { inner };
+        if let Err(e) = dht.handle_packet(&buf[..length], from).await {
+            debug!(?e, %from, "Invalid DHT packet");
+        }
+    }
+}
+
+/// Rotates tokens, forgets expired peers, pings silent nodes and refreshes idle buckets
+async fn maintain(inner: Weak<Inner>) {
+    let mut interval =
+        time::interval_at(Instant::now() + MAINTENANCE_INTERVAL, MAINTENANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let dht = Dht { inner };
+        let now = Instant::now();
+        let (questionable, targets) = {
+            let mut state = dht.lock();
+            if now.duration_since(state.tokens.rotated) > TOKEN_ROTATION {
+                state.tokens.previous = state.tokens.current;
+                state.tokens.current = rand::thread_rng().gen();
+                state.tokens.rotated = now;
+            }
+            for peers in state.peers.values_mut() {
+                peers.retain(|(_, announced)| now.duration_since(*announced) < PEER_TTL);
+            }
+            state.peers.retain(|_, peers| !peers.is_empty());
+            state
+                .external_ip_votes
+                .retain(|_, (_, voted)| now.duration_since(*voted) < IP_VOTE_TTL);
+            (
+                state.table.questionable(now),
+                state.table.refresh_targets(now),
+            )
+        };
+        join_all(questionable.iter().map(|node| dht.ping(node.addr))).await;
+        for target in targets {
+            dht.lookup(target, false).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        encode_peer, is_secure_id, secure_node_id, Arguments, Dht, DhtState, Message, NodeInfo,
+        Response, RoutingTable, Tokens, K, MAX_TORRENTS, MIN_IP_VOTES,
+    };
+    use serde_bytes::ByteBuf;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn it_keeps_the_closest_nodes_by_bucket() {
+        let mut table = RoutingTable::new([0; 20]);
+        let node = |first: u8, last: u8| NodeInfo {
+            id: std::array::from_fn(|i| match i {
+                0 => first,
+                19 => last,
+                _ => 0,
+            }),
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + last as u16),
+        };
+        assert!(!table.insert(node(0, 0)));
+        for last in 0..K as u8 + 1 {
+            table.insert(node(0x80, last));
+        }
+        // The first bucket holds ids starting with a 1 bit, and is full
+        assert_eq!(table.len(), K);
+        assert!(table.insert(node(0x01, 0)));
+        let closest = table.closest(&node(0x01, 1).id, 2);
+        assert_eq!(closest[0], node(0x01, 0));
+        assert_eq!(closest[1], node(0x80, 1));
+    }
+
+    #[tokio::test]
+    async fn it_finds_announced_peers_through_other_nodes() {
+        let mut nodes = Vec::new();
+        for _ in 0..6 {
+            nodes.push(Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap());
+        }
+        let routers = [nodes[0].local_addr().unwrap().to_string()];
+        for node in &nodes[1..] {
+            assert!(node.bootstrap(&routers).await > 0);
+        }
+
+        let info_hash = [7; 20];
+        nodes[1].announce(info_hash, 6000).await;
+        let peers = nodes[5].get_peers(info_hash).await;
+        assert_eq!(peers, [SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6000)]);
+    }
+
+    #[tokio::test]
+    async fn it_bounds_the_torrents_it_stores_peers_for() {
+        let dht = Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let from = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let token = Tokens::token(&dht.lock().tokens.current, from.ip());
+        let info_hash = |index: usize| {
+            let mut info_hash = [0; 20];
+            info_hash[..8].copy_from_slice(&(index as u64).to_be_bytes());
+            info_hash
+        };
+        for index in 0..MAX_TORRENTS + 10 {
+            let arguments = Arguments {
+                id: ByteBuf::from(vec![1; 20]),
+                info_hash: Some(ByteBuf::from(info_hash(index).to_vec())),
+                token: Some(ByteBuf::from(token.clone())),
+                implied_port: Some(1),
+                ..Default::default()
+            };
+            dht.answer("announce_peer", &arguments, from).unwrap();
+        }
+        let state = dht.lock();
+        assert_eq!(state.peers.len(), MAX_TORRENTS);
+        assert!(!state.peers.contains_key(&info_hash(0)));
+        assert!(state.peers.contains_key(&info_hash(MAX_TORRENTS + 9)));
+    }
+
+    #[tokio::test]
+    async fn it_ignores_responses_from_other_nodes() {
+        let dht = Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let queried = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let (reply, mut reply_rx) = oneshot::channel();
+        dht.lock().pending.insert(vec![0, 1], (queried, reply));
+        let response = Message {
+            t: ByteBuf::from(vec![0, 1]),
+            y: "r".to_string(),
+            r: Some(Response {
+                id: ByteBuf::from(vec![2; 20]),
+                ..Default::default()
+            }),
+            ip: Some(ByteBuf::from(encode_peer(&SocketAddrV4::new(
+                Ipv4Addr::new(1, 2, 3, 4),
+                6881,
+            )))),
+            ..Default::default()
+        };
+        let packet = serde_bencode::to_bytes(&response).unwrap();
+
+        let spoofer = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6881);
+        assert!(dht.handle_packet(&packet, spoofer).await.is_err());
+        assert!(reply_rx.try_recv().is_err());
+        assert_eq!(dht.external_ip(), None);
+        dht.handle_packet(&packet, queried).await.unwrap();
+        assert!(reply_rx.try_recv().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_derives_its_id_from_the_agreed_external_ip() {
+        let dht = Dht::with_id("127.0.0.1:0".parse().unwrap(), [0xff; 20])
+            .await
+            .unwrap();
+        let ip = Ipv4Addr::new(124, 31, 75, 21);
+        let voter = |index: u16| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881 + index);
+        // A single node repeating itself is not a majority
+        for _ in 0..MIN_IP_VOTES {
+            dht.lock().vote_external_ip(voter(0), ip);
+        }
+        for index in 1..MIN_IP_VOTES as u16 - 1 {
+            dht.lock().vote_external_ip(voter(index), ip);
+        }
+        assert_eq!(dht.id(), [0xff; 20]);
+        dht.lock().vote_external_ip(voter(MIN_IP_VOTES as u16), ip);
+        assert!(is_secure_id(&dht.id(), &ip));
+        assert_eq!(dht.save_state().external_ip(), Some(ip));
+    }
+
+    #[test]
+    fn it_derives_secure_ids_from_the_external_ip() {
+        // Test vectors from BEP 42
+        for (ip, seed, prefix) in [
+            ([124, 31, 75, 21], 1, [0x5f, 0xbf, 0xb8]),
+            ([21, 75, 31, 124], 86, [0x5a, 0x3c, 0xe8]),
+            ([65, 23, 51, 170], 22, [0xa5, 0xd4, 0x30]),
+            ([84, 124, 73, 14], 65, [0x1b, 0x03, 0x20]),
+            ([43, 213, 53, 83], 90, [0xe5, 0x6f, 0x68]),
+        ] {
+            let ip = Ipv4Addr::from(ip);
+            let id = secure_node_id(&ip, seed);
+            assert_eq!([id[0], id[1], id[2] & 0xf8], prefix);
+            assert_eq!(id[19], seed);
+            assert!(is_secure_id(&id, &ip));
+            assert!(!is_secure_id(&id, &Ipv4Addr::new(1, 2, 3, 4)));
+        }
+        assert!(is_secure_id(&[0; 20], &Ipv4Addr::new(192, 168, 1, 2)));
+    }
+
+    #[tokio::test]
+    async fn it_restores_its_id_and_routing_table() {
+        let router = Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let node = Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        assert!(
+            node.bootstrap(&[router.local_addr().unwrap().to_string()])
+                .await
+                > 0
+        );
+        assert_eq!(node.external_ip(), Some(Ipv4Addr::LOCALHOST));
+
+        let path = std::env::temp_dir().join(format!("furia-{}.dht", std::process::id()));
+        node.save_state().save(&path).await.unwrap();
+        let state = DhtState::load(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(state.nodes(), [router.local_addr().unwrap().to_string()]);
+
+        let restored = Dht::restore("127.0.0.1:0".parse().unwrap(), Some(&state))
+            .await
+            .unwrap();
+        assert_eq!(restored.id(), node.id());
+        assert!(restored.bootstrap(&state.nodes()).await > 0);
+    }
+}
@@ -0,0 +1,598 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use crate::storage::Storage;
+
+#[derive(Debug, Clone)]
+pub struct DiskSettings {
+    /// Number of threads running disk jobs
+    pub threads: usize,
+    /// Number of verified pieces waiting to be written before peers stop getting new requests
+    pub max_queued_writes: usize,
+    /// Bytes of piece data kept in memory to answer reads without touching the disk
+    pub cache_size: usize,
+}
+
+impl Default for DiskSettings {
+    fn default() -> Self {
+        Self {
+            threads: 2,
+            max_queued_writes: 16,
+            cache_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Completion of a job submitted to the disk threads, reported back to the coordinator
+#[derive(Debug)]
+pub enum DiskEvent {
+    /// A verified piece reached the storage, or failed to. The piece's buffer is handed back.
+    Written {
+        piece_index: usize,
+        data: Vec<u8>,
+        result: io::Result<()>,
+    },
+}
+
+enum Job {
+    /// Write of the pending piece at this offset, unless a newer write replaced it
+    Write {
+        piece_index: usize,
+        offset: u64,
+        write_id: u64,
+    },
+    /// Write of a partially downloaded piece, which is never served to peers
+    WritePartial {
+        offset: u64,
+        data: Vec<u8>,
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+    /// Read of the whole piece at `piece_offset`, answering with `length` bytes at `offset`
+    Read {
+        piece_offset: u64,
+        offset: u64,
+        length: usize,
+        reply: oneshot::Sender<io::Result<Vec<u8>>>,
+    },
+    /// Runs once every write queued before it completed
+    Flush {
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+    /// Relocates the storage once every job before it completed, with no other job running
+    Move {
+        directory: PathBuf,
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// Least recently used pieces, bounded by their total size
+struct ReadCache {
+    capacity: usize,
+    size: usize,
+    pieces: HashMap<u64, Arc<Vec<u8>>>,
+    order: VecDeque<u64>,
+}
+
+impl ReadCache {
+    fn get(&mut self, piece_offset: u64) -> Option<Arc<Vec<u8>>> {
+        let piece = self.pieces.get(&piece_offset)?.clone();
+        self.order.retain(|offset| *offset != piece_offset);
+        self.order.push_back(piece_offset);
+        Some(piece)
+    }
+
+    fn insert(&mut self, piece_offset: u64, data: Arc<Vec<u8>>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        self.remove(piece_offset);
+        while self.size + data.len() > self.capacity {
+            let Some(oldest) = self.order.front().copied() else {
+                break;
+            };
+            self.remove(oldest);
+        }
+        self.size += data.len();
+        self.pieces.insert(piece_offset, data);
+        self.order.push_back(piece_offset);
+    }
+
+    fn remove(&mut self, piece_offset: u64) {
+        if let Some(data) = self.pieces.remove(&piece_offset) {
+            self.size -= data.len();
+            self.order.retain(|offset| *offset != piece_offset);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pieces.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    /// Pieces queued or being written, with the id of their latest write, readable until
+    /// they reach the storage
+    pending: BTreeMap<u64, (u64, Arc<Vec<u8>>)>,
+    next_write_id: u64,
+    /// Writes taken by a thread and not finished yet
+    writing: usize,
+    /// Reads taken by a thread and not finished yet
+    reading: usize,
+    /// A move is running and no other job may start
+    moving: bool,
+    cache: ReadCache,
+    closed: bool,
+}
+
+impl State {
+    /// Copies `length` bytes at `offset` out of a pending write or the cache
+    fn lookup(&mut self, piece_offset: u64, offset: u64, length: usize) -> Option<Vec<u8>> {
+        let piece = match self.pending.get(&piece_offset) {
+            Some((_, piece)) => piece.clone(),
+            None => self.cache.get(piece_offset)?,
+        };
+        let start = (offset - piece_offset) as usize;
+        piece.get(start..start + length).map(<[u8]>::to_vec)
+    }
+}
+
+struct Shared {
+    storage: Arc<dyn Storage>,
+    piece_length: u64,
+    state: Mutex<State>,
+    changed: Condvar,
+    events: mpsc::UnboundedSender<DiskEvent>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn piece_offset(&self, offset: u64) -> u64 {
+        offset / self.piece_length * self.piece_length
+    }
+}
+
+/// Runs storage jobs on dedicated threads so that slow disks never block the coordinator.
+/// Adjacent queued pieces are written with a single call, and recently read pieces are
+/// cached for seeding.
+pub struct DiskIo {
+    shared: Arc<Shared>,
+    max_queued_writes: usize,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl DiskIo {
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        piece_length: u64,
+        settings: &DiskSettings,
+    ) -> (Self, mpsc::UnboundedReceiver<DiskEvent>) {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            storage,
+            piece_length: piece_length.max(1),
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                pending: BTreeMap::new(),
+                next_write_id: 0,
+                writing: 0,
+                reading: 0,
+                moving: false,
+                cache: ReadCache {
+                    capacity: settings.cache_size,
+                    size: 0,
+                    pieces: HashMap::new(),
+                    order: VecDeque::new(),
+                },
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            events,
+        });
+        let threads = (0..settings.threads.max(1))
+            .map(|index| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("disk-io-{}", index))
+                    .spawn(move || run_jobs(&shared))
+                    .expect("Failed to spawn disk thread")
+            })
+            .collect();
+        let disk = Self {
+            shared,
+            max_queued_writes: settings.max_queued_writes.max(1),
+            threads,
+        };
+        (disk, events_rx)
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.shared.storage
+    }
+
+    /// Queues a verified piece for writing; completion is reported as [`DiskEvent::Written`]
+    pub fn write_piece(&self, piece_index: usize, offset: u64, data: Vec<u8>) {
+        let mut state = self.shared.lock();
+        let write_id = state.next_write_id;
+        state.next_write_id += 1;
+        state.cache.remove(offset);
+        state.pending.insert(offset, (write_id, Arc::new(data)));
+        state.jobs.push_back(Job::Write {
+            piece_index,
+            offset,
+            write_id,
+        });
+        self.shared.changed.notify_all();
+    }
+
+    /// Writes the received part of an unverified piece, e.g. to save it with the resume data
+    pub async fn write_partial(&self, offset: u64, data: Vec<u8>) -> io::Result<()> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.shared.lock().jobs.push_back(Job::WritePartial {
+            offset,
+            data,
+            reply,
+        });
+        self.shared.changed.notify_all();
+        reply_rx.await.map_err(|_| closed())?
+    }
+
+    /// Whether enough writes are waiting that peers should not be asked for more data
+    pub fn is_congested(&self) -> bool {
+        self.shared.lock().pending.len() >= self.max_queued_writes
+    }
+
+    /// Reads `length` bytes at `offset`, which must lie within a single piece
+    pub async fn read_block(&self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        let piece_offset = self.shared.piece_offset(offset);
+        let reply = {
+            let mut state = self.shared.lock();
+            if let Some(data) = state.lookup(piece_offset, offset, length) {
+                return Ok(data);
+            }
+            let (reply, reply_rx) = oneshot::channel();
+            state.jobs.push_back(Job::Read {
+                piece_offset,
+                offset,
+                length,
+                reply,
+            });
+            self.shared.changed.notify_all();
+            reply_rx
+        };
+        reply.await.map_err(|_| closed())?
+    }
+
+    /// Waits for every queued write, then makes the storage durable
+    pub async fn flush(&self) -> io::Result<()> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.shared.lock().jobs.push_back(Job::Flush { reply });
+        self.shared.changed.notify_all();
+        reply_rx.await.map_err(|_| closed())?
+    }
+
+    /// Moves the files under `directory` once the queued writes are done. Jobs queued
+    /// meanwhile wait, so nothing is written to the old location after the move.
+    pub async fn move_storage(&self, directory: &Path) -> io::Result<()> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.shared.lock().jobs.push_back(Job::Move {
+            directory: directory.to_owned(),
+            reply,
+        });
+        self.shared.changed.notify_all();
+        reply_rx.await.map_err(|_| closed())?
+    }
+
+    /// Forgets cached pieces, e.g. after the data was changed outside of furia
+    pub fn clear_cache(&self) {
+        self.shared.lock().cache.clear();
+    }
+}
+
+impl Drop for DiskIo {
+    /// Lets the threads finish the queued jobs before they exit. Within a runtime they
+    /// are joined on a blocking task, so the worker dropping the last handle goes on.
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+        let threads = std::mem::take(&mut self.threads);
+        let join = move || {
+            for thread in threads {
+                let _ = thread.join();
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(join)),
+            Err(_) => join(),
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::other("Disk threads stopped")
+}
+
+fn run_jobs(shared: &Shared) {
+    loop {
+        let mut state = shared.lock();
+        loop {
+            match state.jobs.front() {
+                _ if state.moving => {}
+                None if state.closed => return,
+                Some(Job::Flush { .. }) if state.writing == 0 => break,
+                Some(Job::Move { .. }) if state.writing == 0 && state.reading == 0 => break,
+                Some(Job::Flush { .. } | Job::Move { .. }) | None => {}
+                Some(_) => break,
+            }
+            state = shared.changed.wait(state).unwrap();
+        }
+        match state.jobs.pop_front() {
+            Some(Job::Write {
+                piece_index,
+                offset,
+                write_id,
+            }) => {
+                let mut writes = vec![(piece_index, offset, write_id)];
+                while let Some(Job::Write {
+                    piece_index,
+                    offset,
+                    write_id,
+                }) = state.jobs.front()
+                {
+                    writes.push((*piece_index, *offset, *write_id));
+                    state.jobs.pop_front();
+                }
+                // A write replaced by a newer one of the same piece is left to the newer job
+                let writes = writes
+                    .into_iter()
+                    .filter_map(|(piece_index, offset, write_id)| {
+                        let (latest, data) = state.pending.get(&offset)?;
+                        (*latest == write_id).then(|| PendingWrite {
+                            piece_index,
+                            offset,
+                            write_id,
+                            data: data.clone(),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                state.writing += writes.len();
+                drop(state);
+                write_coalesced(shared, writes);
+            }
+            Some(Job::WritePartial {
+                offset,
+                data,
+                reply,
+            }) => {
+                state.writing += 1;
+                drop(state);
+                let _ = reply.send(shared.storage.write_piece(offset, &data));
+                shared.lock().writing -= 1;
+                shared.changed.notify_all();
+            }
+            Some(Job::Read {
+                piece_offset,
+                offset,
+                length,
+                reply,
+            }) => {
+                if let Some(data) = state.lookup(piece_offset, offset, length) {
+                    let _ = reply.send(Ok(data));
+                    continue;
+                }
+                state.reading += 1;
+                drop(state);
+                let _ = reply.send(read_piece(shared, piece_offset, offset, length));
+                shared.lock().reading -= 1;
+                shared.changed.notify_all();
+            }
+            Some(Job::Flush { reply }) => {
+                drop(state);
+                let _ = reply.send(shared.storage.flush());
+            }
+            Some(Job::Move { directory, reply }) => {
+                state.moving = true;
+                drop(state);
+                let _ = reply.send(shared.storage.move_to(&directory));
+                shared.lock().moving = false;
+                shared.changed.notify_all();
+            }
+            None => {}
+        }
+    }
+}
+
+struct PendingWrite {
+    piece_index: usize,
+    offset: u64,
+    write_id: u64,
+    data: Arc<Vec<u8>>,
+}
+
+/// Writes runs of adjacent pieces with one storage call each
+fn write_coalesced(shared: &Shared, mut writes: Vec<PendingWrite>) {
+    writes.sort_by_key(|write| write.offset);
+    while !writes.is_empty() {
+        let mut end = 1;
+        while end < writes.len()
+            && writes[end - 1].offset + writes[end - 1].data.len() as u64 == writes[end].offset
+        {
+            end += 1;
+        }
+        let run = writes.drain(..end).collect::<Vec<_>>();
+        let result = if run.len() == 1 {
+            shared.storage.write_piece(run[0].offset, &run[0].data)
+        } else {
+            let data = run
+                .iter()
+                .flat_map(|write| write.data.iter().copied())
+                .collect::<Vec<_>>();
+            shared.storage.write_piece(run[0].offset, &data)
+        };
+        if let Err(e) = &result {
+            warn!(?e, "Failed to write pieces");
+        }
+
+        let mut state = shared.lock();
+        for write in run {
+            if state
+                .pending
+                .get(&write.offset)
+                .is_some_and(|(latest, _)| *latest == write.write_id)
+            {
+                state.pending.remove(&write.offset);
+            }
+            state.writing -= 1;
+            let PendingWrite {
+                piece_index, data, ..
+            } = write;
+            let data = Arc::try_unwrap(data).unwrap_or_else(|data| data.to_vec());
+            let result = match &result {
+                Ok(()) => Ok(()),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            let _ = shared.events.send(DiskEvent::Written {
+                piece_index,
+                data,
+                result,
+            });
+        }
+        drop(state);
+        shared.changed.notify_all();
+    }
+}
+
+/// Reads the whole piece around a block into the cache and returns the block
+fn read_piece(
+    shared: &Shared,
+    piece_offset: u64,
+    offset: u64,
+    length: usize,
+) -> io::Result<Vec<u8>> {
+    let total_length = shared.storage.layout().total_length();
+    let piece_size = shared
+        .piece_length
+        .min(total_length.saturating_sub(piece_offset)) as usize;
+    let start = (offset - piece_offset) as usize;
+    if start + length > piece_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Block crosses a piece boundary",
+        ));
+    }
+    let mut piece = vec![0; piece_size];
+    shared.storage.read_block(piece_offset, &mut piece)?;
+    let block = piece[start..start + length].to_vec();
+    let mut state = shared.lock();
+    if !state.pending.contains_key(&piece_offset) {
+        state.cache.insert(piece_offset, Arc::new(piece));
+    }
+    Ok(block)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DiskEvent, DiskIo, DiskSettings};
+    use crate::storage::{FileLayout, FsStorage, MemoryStorage, Storage};
+    use std::{path::PathBuf, sync::Arc};
+
+    #[tokio::test]
+    async fn it_writes_queued_pieces_and_serves_reads() {
+        let layout = FileLayout::new([(PathBuf::from("test"), 10)]);
+        let storage = Arc::new(MemoryStorage::new(layout));
+        let settings = DiskSettings {
+            threads: 1,
+            max_queued_writes: 2,
+            cache_size: 4,
+        };
+        let (disk, mut events) = DiskIo::new(storage.clone(), 4, &settings);
+
+        disk.write_piece(0, 0, b"abcd".to_vec());
+        disk.write_piece(1, 4, b"efgh".to_vec());
+        disk.write_piece(2, 8, b"ij".to_vec());
+        assert_eq!(disk.read_block(5, 2).await.unwrap(), b"fg");
+        disk.flush().await.unwrap();
+        assert!(!disk.is_congested());
+        assert_eq!(storage.data(), b"abcdefghij");
+
+        let mut written = Vec::new();
+        for _ in 0..3 {
+            let Some(DiskEvent::Written {
+                piece_index,
+                data,
+                result,
+            }) = events.recv().await
+            else {
+                panic!("Disk threads stopped");
+            };
+            result.unwrap();
+            written.push((piece_index, data));
+        }
+        written.sort();
+        assert_eq!(written[2], (2, b"ij".to_vec()));
+
+        assert_eq!(disk.read_block(1, 2).await.unwrap(), b"bc");
+        storage.write_piece(0, b"zzzz").unwrap();
+        assert_eq!(disk.read_block(1, 2).await.unwrap(), b"bc");
+        disk.clear_cache();
+        assert_eq!(disk.read_block(1, 2).await.unwrap(), b"zz");
+    }
+
+    #[tokio::test]
+    async fn it_writes_the_latest_buffer_of_a_piece_queued_twice() {
+        let layout = FileLayout::new([(PathBuf::from("test"), 4)]);
+        let storage = Arc::new(MemoryStorage::new(layout));
+        let settings = DiskSettings {
+            threads: 1,
+            ..Default::default()
+        };
+        let (disk, mut events) = DiskIo::new(storage.clone(), 4, &settings);
+
+        disk.write_piece(0, 0, b"abcd".to_vec());
+        disk.write_piece(0, 0, b"wxyz".to_vec());
+        disk.flush().await.unwrap();
+        assert_eq!(storage.data(), b"wxyz");
+        assert!(!disk.is_congested());
+        let Some(DiskEvent::Written { data, result, .. }) = events.recv().await else {
+            panic!("Disk threads stopped");
+        };
+        result.unwrap();
+        assert_eq!(data, b"wxyz");
+    }
+
+    #[tokio::test]
+    async fn it_moves_storage_after_queued_writes() {
+        let root = std::env::temp_dir().join(format!("furia-disk-{}", std::process::id()));
+        let layout = FileLayout::new([(PathBuf::from("test/a"), 6), (PathBuf::from("test/b"), 4)]);
+        let storage = Arc::new(FsStorage::new(root.join("incomplete"), layout));
+        let (disk, _events) = DiskIo::new(storage.clone(), 4, &DiskSettings::default());
+
+        disk.write_piece(0, 0, b"abcd".to_vec());
+        disk.write_piece(1, 4, b"efgh".to_vec());
+        disk.write_piece(2, 8, b"ij".to_vec());
+        disk.move_storage(&root.join("complete")).await.unwrap();
+        assert_eq!(
+            std::fs::read(root.join("complete/test/a")).unwrap(),
+            b"abcdef"
+        );
+        assert_eq!(
+            std::fs::read(root.join("complete/test/b")).unwrap(),
+            b"ghij"
+        );
+        assert!(!root.join("incomplete/test").exists());
+        assert_eq!(disk.read_block(4, 4).await.unwrap(), b"efgh");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,128 +1,686 @@
-use crate::{messages::BLOCK_BYTES, parse_torrent::TorrentFile};
+use crate::{
+    bitfield::Bitfield, messages::BLOCK_BYTES, parse_torrent::TorrentFile, storage::FileLayout,
+};
 use sha1::{Digest, Sha1};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    time::Instant,
+};
 use tracing::warn;
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum PieceStatus {
-    NotStarted,
-    Downloading,
-    Downloaded,
-    ShaVerified,
-    WrittenToDisk,
+/// Default number of pieces that can be buffered in memory while their blocks arrive
+pub const MAX_PIECES_IN_FLIGHT: usize = 32;
+
+/// Blocks of a piece being downloaded, kept until the piece is verified
+#[derive(Debug, Clone)]
+struct PieceBuffer {
+    data: Vec<u8>,
+    /// Blocks asked to a peer and not received yet
+    requested: Bitfield,
+    received: Bitfield,
+}
+
+impl PieceBuffer {
+    fn next_block(&self) -> Option<usize> {
+        (0..self.received.len())
+            .find(|&block| !self.requested.has(block) && !self.received.has(block))
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Block {
-    NotStarted,
-    Downloaded([u8; BLOCK_BYTES as usize]),
-    Downloading,
+/// How eagerly the pieces of a file are downloaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Not downloaded at all
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Piece {
-    pub content: Vec<Block>,
-    pub status: PieceStatus,
-    pub original_sha1: Vec<u8>,
+/// Order in which new pieces are started, within a priority level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PieceOrder {
+    /// Pieces the fewest peers have first, which keeps them available in the swarm
+    #[default]
+    RarestFirst,
+    /// Lowest index first, for consumers reading the data while it downloads. Within the
+    /// next pieces that fit in memory the rarest still go first.
+    Sequential,
 }
 
 #[derive(Debug, Clone)]
 pub struct Download {
-    pub pieces: Vec<Piece>,
+    hashes: Vec<[u8; 20]>,
+    piece_length: usize,
+    total_length: u64,
+    /// Pieces whose sha1 matched
+    verified: Bitfield,
+    in_flight: BTreeMap<usize, PieceBuffer>,
+    /// Buffers of completed pieces, reused for the next ones
+    buffer_pool: Vec<Vec<u8>>,
+    max_in_flight: usize,
+    /// Position and length of every file in the torrent's content
+    files: Vec<(u64, u64)>,
+    file_priorities: Vec<Priority>,
+    /// Highest priority among the files a piece overlaps
+    piece_priorities: Vec<Priority>,
+    /// First and last pieces of the wanted files
+    edge_pieces: Bitfield,
+    /// Number of connected peers owning each piece
+    availability: Vec<u32>,
+    order: PieceOrder,
+    first_and_last_first: bool,
+    /// Pieces a reader is waiting for, by when they are needed
+    deadlines: HashMap<usize, Instant>,
 }
 
 impl Download {
     pub fn from(torrent: &TorrentFile) -> Self {
-        Self {
-            pieces: torrent
-                .info
-                .pieces
-                .chunks(20)
-                .map(|sha1| Piece {
-                    content: vec![
-                        Block::NotStarted;
-                        (torrent.info.piece_length as u32 / BLOCK_BYTES) as usize
-                    ],
-                    original_sha1: sha1.to_owned(),
-                    status: PieceStatus::NotStarted,
-                })
-                .collect(),
-        }
-    }
-
-    pub fn find_first_block(&self) -> Option<(usize, usize)> {
-        for (piece_index, piece) in self.pieces.iter().enumerate() {
-            for (block_index, block) in piece.content.iter().enumerate() {
-                if *block == Block::NotStarted {
-                    return Some((piece_index, block_index));
-                }
+        let hashes = torrent
+            .info
+            .pieces
+            .chunks_exact(20)
+            .map(|sha1| sha1.try_into().unwrap())
+            .collect::<Vec<[u8; 20]>>();
+        let files = FileLayout::from_torrent(torrent)
+            .files
+            .iter()
+            .map(|file| (file.offset, file.length))
+            .collect::<Vec<_>>();
+        let mut download = Self {
+            verified: Bitfield::new(hashes.len()),
+            piece_priorities: vec![Priority::Normal; hashes.len()],
+            edge_pieces: Bitfield::new(hashes.len()),
+            availability: vec![0; hashes.len()],
+            order: PieceOrder::default(),
+            first_and_last_first: false,
+            deadlines: HashMap::new(),
+            hashes,
+            piece_length: torrent.info.piece_length as usize,
+            total_length: torrent.info.total_length(),
+            in_flight: BTreeMap::new(),
+            buffer_pool: Vec::new(),
+            max_in_flight: MAX_PIECES_IN_FLIGHT,
+            file_priorities: vec![Priority::Normal; files.len()],
+            files,
+        };
+        download.update_piece_priorities();
+        download
+    }
+
+    pub fn set_piece_order(&mut self, order: PieceOrder) {
+        self.order = order;
+    }
+
+    /// Starts the first and last pieces of every wanted file before the others, e.g. so
+    /// that media players can read headers and indexes early
+    pub fn set_first_and_last_first(&mut self, first_and_last_first: bool) {
+        self.first_and_last_first = first_and_last_first;
+    }
+
+    /// Counts the pieces of a newly known peer bitfield
+    pub fn add_availability(&mut self, bitfield: &Bitfield) {
+        for (piece_index, count) in self.availability.iter_mut().enumerate() {
+            if bitfield.has(piece_index) {
+                *count += 1;
             }
         }
-        None
     }
 
-    pub fn set_piece(&mut self, data: &[u8], piece_index: usize) -> () {
-        let mut hasher = Sha1::new();
-        hasher.update(&data);
-        let info_hash = hasher.finalize();
-
-        if info_hash.as_slice() == self.pieces[piece_index].original_sha1.as_slice() {
-            self.pieces[piece_index].status = PieceStatus::ShaVerified;
-            self.pieces[piece_index].content = data
-                .chunks(BLOCK_BYTES as usize)
-                .map(|block| Block::Downloaded(block.try_into().unwrap()))
-                .collect();
+    /// Forgets the pieces of a peer that disconnected or replaced its bitfield
+    pub fn remove_availability(&mut self, bitfield: &Bitfield) {
+        for (piece_index, count) in self.availability.iter_mut().enumerate() {
+            if bitfield.has(piece_index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Counts a piece a peer announced with `have`
+    pub fn add_have(&mut self, piece_index: usize) {
+        if let Some(count) = self.availability.get_mut(piece_index) {
+            *count += 1;
+        }
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn file_priority(&self, file_index: usize) -> Priority {
+        self.file_priorities[file_index]
+    }
+
+    pub fn piece_priority(&self, piece_index: usize) -> Priority {
+        self.piece_priorities[piece_index]
+    }
+
+    /// Changes how eagerly a file is downloaded. Pieces shared with another file
+    /// take the highest priority of the two, and buffered pieces that are no longer
+    /// wanted are dropped.
+    pub fn set_file_priority(&mut self, file_index: usize, priority: Priority) {
+        self.file_priorities[file_index] = priority;
+        self.update_piece_priorities();
+
+        let skipped = self
+            .in_flight
+            .keys()
+            .copied()
+            .filter(|&piece_index| self.piece_priorities[piece_index] == Priority::Skip)
+            .collect::<Vec<_>>();
+        for piece_index in skipped {
+            let buffer = self.in_flight.remove(&piece_index).unwrap();
+            self.release_buffer(buffer.data);
+        }
+    }
+
+    fn update_piece_priorities(&mut self) {
+        self.piece_priorities.fill(Priority::Skip);
+        self.edge_pieces = Bitfield::new(self.piece_count());
+        if self.piece_count() == 0 {
+            return;
+        }
+        let piece_length = self.piece_length.max(1) as u64;
+        for (&(offset, length), &priority) in self.files.iter().zip(&self.file_priorities) {
+            if length == 0 || offset >= self.total_length {
+                continue;
+            }
+            let first = (offset / piece_length) as usize;
+            let last =
+                (((offset + length - 1) / piece_length) as usize).min(self.piece_count() - 1);
+            for piece_priority in &mut self.piece_priorities[first..=last] {
+                *piece_priority = (*piece_priority).max(priority);
+            }
+            if priority != Priority::Skip {
+                self.edge_pieces.set(first);
+                self.edge_pieces.set(last);
+            }
+        }
+    }
+
+    /// Asks for a missing piece to be downloaded before the others, even from a skipped file.
+    /// The earliest deadline is kept until the piece is verified.
+    pub fn set_deadline(&mut self, piece_index: usize, deadline: Instant) {
+        if piece_index >= self.piece_count() || self.has_piece(piece_index) {
+            return;
+        }
+        let current = self.deadlines.entry(piece_index).or_insert(deadline);
+        *current = (*current).min(deadline);
+    }
+
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    fn deadline(&self, piece_index: usize) -> Option<Reverse<Instant>> {
+        self.deadlines.get(&piece_index).copied().map(Reverse)
+    }
+
+    fn is_wanted(&self, piece_index: usize) -> bool {
+        self.piece_priorities[piece_index] != Priority::Skip
+            || self.deadlines.contains_key(&piece_index)
+    }
+
+    /// Caps how many partially downloaded pieces are held in memory at once
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.max_in_flight = max_in_flight.max(1);
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Size of a piece, only the last one can be shorter than the piece length
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        let start = (piece_index * self.piece_length) as u64;
+        (self.total_length.saturating_sub(start) as usize).min(self.piece_length)
+    }
+
+    /// Position of the piece in the torrent's content
+    pub fn piece_offset(&self, piece_index: usize) -> u64 {
+        piece_index as u64 * self.piece_length as u64
+    }
+
+    /// Index of the piece holding the byte at `offset` in the torrent's content
+    pub fn piece_at(&self, offset: u64) -> usize {
+        (offset / self.piece_length.max(1) as u64) as usize
+    }
+
+    pub fn block_count(&self, piece_index: usize) -> usize {
+        self.piece_size(piece_index).div_ceil(BLOCK_BYTES as usize)
+    }
+
+    /// Size of a block, only the last block of the last piece can be shorter than `BLOCK_BYTES`
+    pub fn block_size(&self, piece_index: usize, block_index: usize) -> usize {
+        let start = block_index * BLOCK_BYTES as usize;
+        (self.piece_size(piece_index).saturating_sub(start)).min(BLOCK_BYTES as usize)
+    }
+
+    /// Whether a block request or reply from a peer fits in the torrent: at most
+    /// `BLOCK_BYTES` long and within an existing piece
+    pub fn is_valid_block(&self, piece_index: usize, offset: usize, length: usize) -> bool {
+        piece_index < self.piece_count()
+            && length > 0
+            && length <= BLOCK_BYTES as usize
+            && offset
+                .checked_add(length)
+                .is_some_and(|end| end <= self.piece_size(piece_index))
+    }
+
+    /// Reserves the next block to request from a peer owning `bitfield`, as
+    /// (piece index, block index). Blocks of pieces already buffered come first,
+    /// a new piece is only started when the buffer pool has room, picking the
+    /// one with the earliest deadline, then the highest priority one and then
+    /// following the piece order.
+    pub fn reserve_block(&mut self, bitfield: &Bitfield) -> Option<(usize, usize)> {
+        let in_flight = self
+            .in_flight
+            .iter()
+            .filter(|(piece_index, _)| bitfield.has(**piece_index))
+            .filter(|(piece_index, _)| self.is_wanted(**piece_index))
+            .filter_map(|(piece_index, buffer)| Some((*piece_index, buffer.next_block()?)))
+            .max_by_key(|(piece_index, _)| (self.deadline(*piece_index), Reverse(*piece_index)));
+        if let Some((piece_index, block_index)) = in_flight {
+            self.in_flight
+                .get_mut(&piece_index)
+                .unwrap()
+                .requested
+                .set(block_index);
+            return Some((piece_index, block_index));
+        }
+
+        if self.in_flight.len() >= self.max_in_flight && !self.evict_stranded_piece() {
+            return None;
+        }
+        let window_end = (0..self.piece_count())
+            .find(|&piece_index| self.is_wanted(piece_index) && !self.has_piece(piece_index))
+            .unwrap_or(0)
+            + self.max_in_flight;
+        let piece_index = (0..self.piece_count())
+            .filter(|&piece_index| {
+                bitfield.has(piece_index)
+                    && self.is_wanted(piece_index)
+                    && !self.has_piece(piece_index)
+                    && !self.in_flight.contains_key(&piece_index)
+            })
+            .max_by_key(|&piece_index| {
+                let edge = self.first_and_last_first && self.edge_pieces.has(piece_index);
+                let rarity = match self.order {
+                    PieceOrder::Sequential if piece_index >= window_end => (false, Reverse(0)),
+                    _ => (true, Reverse(self.availability[piece_index])),
+                };
+                (
+                    self.deadline(piece_index),
+                    self.piece_priorities[piece_index],
+                    edge,
+                    rarity,
+                    Reverse(piece_index),
+                )
+            })?;
+        let mut buffer = PieceBuffer {
+            data: self.buffer_pool.pop().unwrap_or_default(),
+            requested: Bitfield::new(self.block_count(piece_index)),
+            received: Bitfield::new(self.block_count(piece_index)),
+        };
+        buffer.data.resize(self.piece_size(piece_index), 0);
+        buffer.requested.set(0);
+        self.in_flight.insert(piece_index, buffer);
+        Some((piece_index, 0))
+    }
+
+    /// Frees the buffer of a piece nobody is fetching and no connected peer has, or that is
+    /// no longer wanted, so that it can't hold a slot forever. The piece with the fewest
+    /// blocks received goes first; its blocks are downloaded again if it is started later.
+    fn evict_stranded_piece(&mut self) -> bool {
+        let stranded = self
+            .in_flight
+            .iter()
+            .filter(|(piece_index, buffer)| {
+                (self.availability[**piece_index] == 0 || !self.is_wanted(**piece_index))
+                    && buffer.requested.count() == 0
+            })
+            .min_by_key(|(_, buffer)| buffer.received.count())
+            .map(|(piece_index, _)| *piece_index);
+        let Some(buffer) = stranded.and_then(|piece_index| self.in_flight.remove(&piece_index))
+        else {
+            return false;
+        };
+        self.release_buffer(buffer.data);
+        true
+    }
+
+    /// Reserves every block left to request in a piece, for sources that fetch
+    /// byte ranges rather than blocks, as (piece index, blocks to fetch)
+    pub fn reserve_blocks(&mut self, bitfield: &Bitfield) -> Option<(usize, Range<usize>)> {
+        let (piece_index, first) = self.reserve_block(bitfield)?;
+        let buffer = self.in_flight.get_mut(&piece_index)?;
+        let mut last = first;
+        while let Some(block_index) = buffer.next_block() {
+            buffer.requested.set(block_index);
+            last = last.max(block_index);
+        }
+        Some((piece_index, first..last + 1))
+    }
+
+    pub fn has_piece(&self, piece_index: usize) -> bool {
+        self.verified.has(piece_index)
+    }
+
+    /// Marks a piece as present without hashing it
+    pub fn set_verified(&mut self, piece_index: usize) {
+        self.verified.set(piece_index);
+        self.in_flight.remove(&piece_index);
+        self.deadlines.remove(&piece_index);
+    }
+
+    /// Forgets a verified piece, e.g. when it could not be written to storage
+    pub fn set_unverified(&mut self, piece_index: usize) {
+        self.verified.clear(piece_index);
+    }
+
+    pub fn piece_hash(&self, piece_index: usize) -> [u8; 20] {
+        self.hashes[piece_index]
+    }
+
+    /// Replaces the verified pieces with the outcome of a hash check
+    pub fn set_checked(&mut self, verified: &Bitfield) {
+        for piece_index in 0..self.piece_count() {
+            if verified.has(piece_index) {
+                self.set_verified(piece_index);
+            } else {
+                self.verified.clear(piece_index);
+            }
+        }
+    }
+
+    /// Pieces we can serve, as advertised in our `bitfield` message
+    pub fn bitfield(&self) -> Bitfield {
+        self.verified.clone()
+    }
+
+    /// Whether the peer owning `bitfield` has any wanted piece we are missing
+    pub fn wants(&self, bitfield: &Bitfield) -> bool {
+        (0..self.piece_count()).any(|piece_index| {
+            bitfield.has(piece_index) && self.is_wanted(piece_index) && !self.has_piece(piece_index)
+        })
+    }
+
+    /// Whether every piece of the files we did not skip is verified
+    pub fn is_complete(&self) -> bool {
+        (0..self.piece_count())
+            .all(|piece_index| self.has_piece(piece_index) || !self.is_wanted(piece_index))
+    }
+
+    /// Whether every piece is verified, skipped files included
+    pub fn has_all_pieces(&self) -> bool {
+        (0..self.piece_count()).all(|piece_index| self.has_piece(piece_index))
+    }
+
+    /// Puts a block that was requested but never received back in the queue
+    pub fn reclaim_block(&mut self, piece_index: usize, block_index: usize) {
+        if let Some(buffer) = self.in_flight.get_mut(&piece_index) {
+            buffer.requested.clear(block_index);
+        }
+    }
+
+    /// Checks a whole piece read back from disk
+    pub fn set_piece(&mut self, data: &[u8], piece_index: usize) {
+        if self.verify(data, piece_index) {
+            self.set_verified(piece_index);
         } else {
             warn!("Data not valid for piece {}", piece_index);
-            self.pieces[piece_index].status = PieceStatus::NotStarted;
         }
     }
 
-    pub fn set_block(&mut self, data: &[u8], piece_index: usize, piece_offset: usize) -> Option<Vec<u8>> {
-        self.pieces[piece_index as usize].content[piece_offset / BLOCK_BYTES as usize] =
-            Block::Downloaded(data.try_into().unwrap());
+    /// Stores a received block. Once every block of the piece arrived and its sha1
+    /// matches, the piece data is handed back to be written out, after which the
+    /// caller should return it with `release_buffer`.
+    pub fn set_block(
+        &mut self,
+        data: &[u8],
+        piece_index: usize,
+        piece_offset: usize,
+    ) -> Option<Vec<u8>> {
+        let block_index = piece_offset / BLOCK_BYTES as usize;
+        if !piece_offset.is_multiple_of(BLOCK_BYTES as usize)
+            || data.len() != self.block_size(piece_index, block_index)
+        {
+            warn!("Unexpected block geometry for piece {}", piece_index);
+            return None;
+        }
+        let buffer = self.in_flight.get_mut(&piece_index)?;
+        let end = piece_offset + data.len();
+        buffer.data[piece_offset..end].copy_from_slice(data);
+        buffer.requested.clear(block_index);
+        buffer.received.set(block_index);
+        if buffer.received.count() < buffer.received.len() {
+            return None;
+        }
+
+        let buffer = self.in_flight.remove(&piece_index)?;
+        if self.verify(&buffer.data, piece_index) {
+            self.verified.set(piece_index);
+            self.deadlines.remove(&piece_index);
+            Some(buffer.data)
+        } else {
+            warn!("Failed to download piece");
+            self.in_flight.insert(
+                piece_index,
+                PieceBuffer {
+                    requested: Bitfield::new(self.block_count(piece_index)),
+                    received: Bitfield::new(self.block_count(piece_index)),
+                    ..buffer
+                },
+            );
+            None
+        }
+    }
 
-        if self.pieces[piece_index as usize]
-            .content
+    /// Pieces with some blocks received, as (piece index, received blocks, piece data)
+    pub fn partial_pieces(&self) -> impl Iterator<Item = (usize, &Bitfield, &[u8])> {
+        self.in_flight
             .iter()
-            .all(|block| *block != Block::NotStarted && *block != Block::Downloading)
-        {
-            self.pieces[piece_index as usize].status = PieceStatus::Downloaded;
-            let data = self.pieces[piece_index as usize]
-                .content
-                .iter()
-                .map(|block| match block {
-                    Block::Downloaded(data) => data.to_vec(),
-                    _ => panic!("Block not downloaded"),
-                })
-                .flatten()
-                .collect::<Vec<u8>>();
-
-            let mut hasher = Sha1::new();
-            hasher.update(&data);
-            let info_hash = hasher.finalize();
-            if info_hash.as_slice() == self.pieces[piece_index as usize].original_sha1.as_slice() {
-                self.pieces[piece_index as usize].status = PieceStatus::ShaVerified;
-                return Some(data)
-            } else {
-                warn!("Failed to download piece");
-                self.pieces[piece_index as usize].status = PieceStatus::NotStarted;
-                return None
-            };
+            .filter(|(_, buffer)| buffer.received.count() > 0)
+            .map(|(piece_index, buffer)| (*piece_index, &buffer.received, buffer.data.as_slice()))
+    }
+
+    /// Buffers a piece whose `received` blocks were saved by a previous run
+    pub fn restore_partial(&mut self, piece_index: usize, received: Bitfield, data: Vec<u8>) {
+        if self.has_piece(piece_index) || self.in_flight.len() >= self.max_in_flight {
+            return;
+        }
+        self.in_flight.insert(
+            piece_index,
+            PieceBuffer {
+                data,
+                requested: Bitfield::new(received.len()),
+                received,
+            },
+        );
+    }
+
+    /// Bytes of the wanted pieces we are still missing
+    pub fn bytes_left(&self) -> u64 {
+        (0..self.piece_count())
+            .filter(|&piece_index| self.is_wanted(piece_index) && !self.has_piece(piece_index))
+            .map(|piece_index| self.piece_size(piece_index) as u64)
+            .sum()
+    }
+
+    /// Returns the buffer of a written piece to the pool
+    pub fn release_buffer(&mut self, buffer: Vec<u8>) {
+        if self.buffer_pool.len() + self.in_flight.len() < self.max_in_flight {
+            self.buffer_pool.push(buffer);
+        }
+    }
+
+    fn verify(&self, data: &[u8], piece_index: usize) -> bool {
+        if data.len() != self.piece_size(piece_index) {
+            return false;
         }
-        None
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.finalize().as_slice() == self.hashes[piece_index]
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::parse_torrent;
-    use super::Download;
-    
+    use super::{Download, PieceOrder, Priority};
+    use crate::bitfield::Bitfield;
+    use crate::parse_torrent::{multi_file_torrent, parse_torrent, single_file_torrent};
+    use std::time::Instant;
+
     #[test]
-    fn it_sets_invalid_pieces(){
-        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+    fn it_sets_invalid_pieces() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent").unwrap();
         let mut download = Download::from(&torrent);
         download.set_piece(&vec![0; torrent.info.piece_length as usize], 0);
-        assert_eq!(download.pieces[0].status, super::PieceStatus::NotStarted);
-    } 
+        assert!(!download.has_piece(0));
+    }
+
+    #[test]
+    fn it_wants_pieces_the_peer_has_and_we_miss() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent").unwrap();
+        let mut download = Download::from(&torrent);
+        let mut bitfield = Bitfield::new(download.piece_count());
+        assert!(!download.wants(&bitfield));
+
+        bitfield.set(1);
+        assert!(download.wants(&bitfield));
+        assert_eq!(download.reserve_block(&bitfield), Some((1, 0)));
+
+        download.set_verified(1);
+        assert!(!download.wants(&bitfield));
+    }
+
+    #[test]
+    fn it_bounds_pieces_in_flight() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent").unwrap();
+        let mut download = Download::from(&torrent);
+        download.set_max_in_flight(1);
+        let mut bitfield = Bitfield::new(download.piece_count());
+        bitfield.set(0);
+        bitfield.set(1);
+
+        let blocks_per_piece = torrent.info.piece_length as usize / 16384;
+        for block_index in 0..blocks_per_piece {
+            assert_eq!(download.reserve_block(&bitfield), Some((0, block_index)));
+        }
+        assert_eq!(download.reserve_block(&bitfield), None);
+
+        download.reclaim_block(0, 3);
+        assert_eq!(download.reserve_block(&bitfield), Some((0, 3)));
+
+        // Once its only peer is gone, the piece no longer holds the slot
+        download.add_availability(&bitfield);
+        download.remove_availability(&bitfield);
+        for block_index in 0..blocks_per_piece {
+            download.reclaim_block(0, block_index);
+        }
+        let mut other = Bitfield::new(download.piece_count());
+        other.set(2);
+        download.add_availability(&other);
+        assert_eq!(download.reserve_block(&other), Some((2, 0)));
+    }
+
+    #[test]
+    fn it_sizes_the_last_piece_and_block() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent").unwrap();
+        let download = Download::from(&torrent);
+        assert_eq!(download.piece_count(), 8139);
+        assert_eq!(download.piece_size(0), 262144);
+        assert_eq!(download.block_count(0), 16);
+        assert_eq!(download.piece_size(8138), 63488);
+        assert_eq!(download.block_count(8138), 4);
+        assert_eq!(download.block_size(8138, 2), 16384);
+        assert_eq!(download.block_size(8138, 3), 14336);
+        assert!(download.is_valid_block(8138, 3 * 16384, 14336));
+        assert!(!download.is_valid_block(8138, 3 * 16384, 16384));
+        assert!(!download.is_valid_block(8139, 0, 16384));
+        assert!(!download.is_valid_block(0, usize::MAX, 1));
+        assert!(!download.is_valid_block(0, 0, 32768));
+    }
+
+    #[test]
+    fn it_completes_a_short_last_piece() {
+        let data = (0..40000_u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let torrent = single_file_torrent(&data, 32768);
+        let mut download = Download::from(&torrent);
+        let mut bitfield = Bitfield::new(2);
+        bitfield.set(1);
+
+        assert_eq!(download.reserve_block(&bitfield), Some((1, 0)));
+        assert_eq!(download.block_size(1, 0), 40000 - 32768);
+        assert_eq!(
+            download.set_block(&data[32768..], 1, 0),
+            Some(data[32768..].to_vec())
+        );
+        assert!(download.has_piece(1));
+    }
+
+    #[test]
+    fn it_picks_pieces_by_file_priority() {
+        let data = vec![7; 100];
+        let torrent = multi_file_torrent(&data, 10, &[("a", 25), ("b", 35), ("c", 40)]);
+        let mut download = Download::from(&torrent);
+        download.set_max_in_flight(1);
+        download.set_file_priority(0, Priority::Skip);
+        download.set_file_priority(2, Priority::High);
+        let mut bitfield = Bitfield::new(10);
+        for piece_index in 0..10 {
+            bitfield.set(piece_index);
+        }
+
+        assert_eq!(download.piece_priority(1), Priority::Skip);
+        assert_eq!(download.piece_priority(2), Priority::Normal);
+        assert_eq!(download.piece_priority(6), Priority::High);
+        assert_eq!(download.bytes_left(), 80);
+        assert_eq!(download.reserve_block(&bitfield), Some((6, 0)));
+
+        download.set_file_priority(2, Priority::Skip);
+        assert_eq!(download.reserve_block(&bitfield), Some((2, 0)));
+        for piece_index in 2..6 {
+            download.set_verified(piece_index);
+        }
+        assert!(download.is_complete());
+        assert!(!download.wants(&bitfield));
+    }
+
+    #[test]
+    fn it_follows_the_piece_order() {
+        let data = vec![7; 100];
+        let torrent = single_file_torrent(&data, 10);
+        let mut download = Download::from(&torrent);
+        download.set_max_in_flight(3);
+        let mut everything = Bitfield::new(10);
+        let mut common = Bitfield::new(10);
+        for piece_index in 0..10 {
+            everything.set(piece_index);
+            if piece_index != 1 && piece_index != 6 {
+                common.set(piece_index);
+            }
+        }
+        download.add_availability(&everything);
+        download.add_availability(&common);
+
+        let mut rarest = download.clone();
+        assert_eq!(rarest.reserve_block(&everything), Some((1, 0)));
+        assert_eq!(rarest.reserve_block(&everything), Some((6, 0)));
+
+        download.set_piece_order(PieceOrder::Sequential);
+        download.set_first_and_last_first(true);
+        assert_eq!(download.reserve_block(&everything), Some((0, 0)));
+        assert_eq!(download.reserve_block(&everything), Some((9, 0)));
+        assert_eq!(download.reserve_block(&everything), Some((1, 0)));
+        download.set_verified(0);
+        download.set_verified(1);
+        download.set_verified(9);
+        assert_eq!(download.reserve_block(&everything), Some((2, 0)));
+        download.set_deadline(7, Instant::now());
+        assert_eq!(download.reserve_block(&everything), Some((7, 0)));
+    }
 }
@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Result};
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{messages::ExtendedHandshake, pex, tracker::Peer};
+
+/// First message id given to registered extensions, lower ones belong to built-in extensions
+const FIRST_REGISTERED_ID: u8 = 2;
+
+/// An extension of the extension protocol (BEP 10), handling the messages sent under its name
+pub trait ExtensionHandler: Send + Sync {
+    /// Name in the handshake's `m` dictionary, such as `ut_metadata`
+    fn name(&self) -> &str;
+
+    /// Adds the extension's own keys to the handshake we send
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called with the handshake of a peer that supports the extension
+    fn peer_handshake(&self, _peer: &Peer, _handshake: &ExtendedHandshake) {}
+
+    /// Handles a message from a peer, returning payloads to answer with. An error
+    /// disconnects the peer.
+    fn message(&self, peer: &Peer, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+}
+
+/// Registered extensions, by the message id peers send them to us with
+#[derive(Default, Clone)]
+pub struct ExtensionRegistry {
+    handlers: BTreeMap<u8, Arc<dyn ExtensionHandler>>,
+}
+
+impl ExtensionRegistry {
+    /// Adds an extension, returning the message id it is advertised with
+    pub fn register(&mut self, handler: Arc<dyn ExtensionHandler>) -> Result<u8> {
+        let name = handler.name();
+        if name == pex::EXTENSION_NAME || self.handlers.values().any(|h| h.name() == name) {
+            return Err(anyhow!("Extension {} is already registered", name));
+        }
+        let id = u8::try_from(self.handlers.len())
+            .ok()
+            .and_then(|count| count.checked_add(FIRST_REGISTERED_ID))
+            .ok_or_else(|| anyhow!("No message id left for extension {}", name))?;
+        self.handlers.insert(id, handler);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Arc<dyn ExtensionHandler>> {
+        self.handlers.get(&id)
+    }
+
+    /// Advertises every registered extension in our handshake
+    pub fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        for (id, handler) in &self.handlers {
+            handshake.m.insert(handler.name().to_string(), *id as i64);
+            handler.extend_handshake(handshake);
+        }
+    }
+
+    /// Hands a peer's handshake to the extensions it supports
+    pub fn peer_handshake(&self, peer: &Peer, handshake: &ExtendedHandshake) {
+        for handler in self.handlers.values() {
+            if handshake.m.get(handler.name()).is_some_and(|id| *id != 0) {
+                handler.peer_handshake(peer, handshake);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ExtensionHandler, ExtensionRegistry, FIRST_REGISTERED_ID};
+    use crate::{messages::ExtendedHandshake, pex, tracker::Peer};
+    use anyhow::Result;
+    use std::sync::Arc;
+
+    struct Named(&'static str);
+
+    impl ExtensionHandler for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn message(&self, _peer: &Peer, _payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn it_gives_registered_extensions_their_own_ids() {
+        let mut registry = ExtensionRegistry::default();
+        assert_eq!(
+            registry.register(Arc::new(Named("ut_metadata"))).unwrap(),
+            FIRST_REGISTERED_ID
+        );
+        assert_eq!(
+            registry.register(Arc::new(Named("lt_donthave"))).unwrap(),
+            FIRST_REGISTERED_ID + 1
+        );
+        assert!(registry.register(Arc::new(Named("ut_metadata"))).is_err());
+        assert!(registry
+            .register(Arc::new(Named(pex::EXTENSION_NAME)))
+            .is_err());
+
+        let mut handshake = ExtendedHandshake::default();
+        registry.extend_handshake(&mut handshake);
+        assert_eq!(handshake.m["lt_donthave"], FIRST_REGISTERED_ID as i64 + 1);
+        assert_eq!(
+            registry.get(FIRST_REGISTERED_ID).unwrap().name(),
+            "ut_metadata"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, watch};
+use tracing::info;
+
+use crate::{bitfield::Bitfield, download::Download, storage::Storage};
+
+/// How far along a hash check is
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CheckProgress {
+    pub checked: usize,
+    pub verified: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckSettings {
+    /// Number of threads reading and hashing pieces
+    pub threads: usize,
+    /// Caps the combined read rate of all threads
+    pub max_bytes_per_second: Option<u64>,
+}
+
+impl Default for CheckSettings {
+    fn default() -> Self {
+        Self {
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            max_bytes_per_second: None,
+        }
+    }
+}
+
+struct PieceCheck {
+    offset: u64,
+    size: usize,
+    hash: [u8; 20],
+}
+
+/// Shared read budget: threads sleep when the bytes read so far are ahead of the allowed rate
+struct Throttle {
+    start: Instant,
+    bytes: AtomicU64,
+    max_bytes_per_second: Option<u64>,
+}
+
+impl Throttle {
+    fn consume(&self, bytes: usize) {
+        let total = self.bytes.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        if let Some(rate) = self.max_bytes_per_second {
+            let due = Duration::from_secs_f64(total as f64 / rate.max(1) as f64);
+            if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+    }
+}
+
+/// Hashes every piece of `storage` on blocking threads, reporting progress on
+/// `progress` as pieces complete, and returns the pieces that matched their sha1
+pub async fn check_pieces(
+    storage: Arc<dyn Storage>,
+    download: &Download,
+    settings: &CheckSettings,
+    progress: &watch::Sender<CheckProgress>,
+) -> Result<Bitfield> {
+    let total = download.piece_count();
+    let mut verified = Bitfield::new(total);
+    progress.send_replace(CheckProgress {
+        total,
+        ..Default::default()
+    });
+    let files = storage.layout().files.len();
+    if !(0..files).any(|file_index| storage.file_exists(file_index)) {
+        return Ok(verified);
+    }
+
+    let pieces = Arc::new(
+        (0..total)
+            .map(|piece_index| PieceCheck {
+                offset: download.piece_offset(piece_index),
+                size: download.piece_size(piece_index),
+                hash: download.piece_hash(piece_index),
+            })
+            .collect::<Vec<_>>(),
+    );
+    let next_piece = Arc::new(AtomicUsize::new(0));
+    let throttle = Arc::new(Throttle {
+        start: Instant::now(),
+        bytes: AtomicU64::new(0),
+        max_bytes_per_second: settings.max_bytes_per_second,
+    });
+    let (results_tx, mut results_rx) = mpsc::unbounded_channel();
+
+    let workers = (0..settings.threads.max(1))
+        .map(|_| {
+            let storage = storage.clone();
+            let pieces = pieces.clone();
+            let next_piece = next_piece.clone();
+            let throttle = throttle.clone();
+            let results_tx = results_tx.clone();
+            tokio::task::spawn_blocking(move || {
+                check_worker(
+                    storage.as_ref(),
+                    &pieces,
+                    &next_piece,
+                    &throttle,
+                    &results_tx,
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(results_tx);
+
+    let mut checked = 0;
+    while let Some((piece_index, matches)) = results_rx.recv().await {
+        checked += 1;
+        if matches {
+            verified.set(piece_index);
+        }
+        if checked * 10 / total != (checked - 1) * 10 / total {
+            info!("Checked {}/{} pieces", checked, total);
+        }
+        progress.send_replace(CheckProgress {
+            checked,
+            verified: verified.count(),
+            total,
+        });
+    }
+    for worker in workers {
+        worker.await??;
+    }
+    Ok(verified)
+}
+
+fn check_worker(
+    storage: &dyn Storage,
+    pieces: &[PieceCheck],
+    next_piece: &AtomicUsize,
+    throttle: &Throttle,
+    results: &mpsc::UnboundedSender<(usize, bool)>,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    loop {
+        let piece_index = next_piece.fetch_add(1, Ordering::Relaxed);
+        let Some(piece) = pieces.get(piece_index) else {
+            return Ok(());
+        };
+        buffer.resize(piece.size, 0);
+        let matches = match storage.read_block(piece.offset, &mut buffer) {
+            Ok(()) => Sha1::digest(&buffer).as_slice() == piece.hash,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof | io::ErrorKind::NotFound
+                ) =>
+            {
+                false
+            }
+            Err(e) => return Err(e.into()),
+        };
+        throttle.consume(piece.size);
+        results
+            .send((piece_index, matches))
+            .map_err(|_| anyhow!("Hash check cancelled"))?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_pieces, CheckProgress, CheckSettings};
+    use crate::{
+        download::Download,
+        parse_torrent::single_file_torrent,
+        storage::{FileLayout, MemoryStorage},
+    };
+    use std::{path::PathBuf, sync::Arc};
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn it_checks_pieces_in_parallel() {
+        let mut data = (0..100_000_u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let torrent = single_file_torrent(&data, 16384);
+        let download = Download::from(&torrent);
+        data[16384] ^= 1;
+        data.truncate(90_000);
+        let layout = FileLayout::new([(PathBuf::from("test"), 100_000)]);
+        let storage = Arc::new(MemoryStorage::with_data(layout, data));
+
+        let (progress_tx, progress_rx) = watch::channel(CheckProgress::default());
+        let settings = CheckSettings {
+            threads: 3,
+            max_bytes_per_second: None,
+        };
+        let verified = check_pieces(storage, &download, &settings, &progress_tx)
+            .await
+            .unwrap();
+
+        let pieces = (0..download.piece_count())
+            .map(|piece_index| verified.has(piece_index))
+            .collect::<Vec<_>>();
+        assert_eq!(pieces, vec![true, false, true, true, true, false, false]);
+        assert_eq!(
+            *progress_rx.borrow(),
+            CheckProgress {
+                checked: 7,
+                verified: 4,
+                total: 7
+            }
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt;
+
+use crate::{parse_torrent::TorrentFile, tracker::get_info_hash};
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Everything a torrent file tells about its content, for `furia info`
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub info_hash: String,
+    pub info_hash_base32: String,
+    pub name: String,
+    pub total_size: u64,
+    pub piece_length: i64,
+    pub piece_count: usize,
+    pub files: Vec<FileSummary>,
+    /// Tracker URLs, by tier
+    pub trackers: Vec<Vec<String>>,
+    pub web_seeds: Vec<String>,
+    pub http_seeds: Vec<String>,
+    /// DHT nodes as `host:port`
+    pub nodes: Vec<String>,
+    pub private: bool,
+    /// Seconds since the Unix epoch
+    pub creation_date: Option<i64>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileSummary {
+    pub path: String,
+    pub size: u64,
+}
+
+impl Summary {
+    pub fn new(torrent: &TorrentFile) -> Result<Self> {
+        let info_hash = get_info_hash(&torrent.info)?;
+        let info = &torrent.info;
+        let files = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| FileSummary {
+                    path: file.path.join("/"),
+                    size: file.length as u64,
+                })
+                .collect(),
+            None => vec![FileSummary {
+                path: info.name.clone(),
+                size: info.total_length(),
+            }],
+        };
+        Ok(Self {
+            info_hash: hex::encode(info_hash),
+            info_hash_base32: base32(&info_hash),
+            name: info.name.clone(),
+            total_size: info.total_length(),
+            piece_length: info.piece_length,
+            piece_count: info.piece_count(),
+            files,
+            trackers: torrent.trackers(),
+            web_seeds: torrent.web_seeds(),
+            http_seeds: torrent.httpseeds.clone().unwrap_or_default(),
+            nodes: torrent
+                .nodes
+                .iter()
+                .flatten()
+                .map(|node| format!("{}:{}", node.0, node.1))
+                .collect(),
+            private: torrent.is_private(),
+            creation_date: torrent.creation_date,
+            comment: torrent.comment.clone(),
+            created_by: torrent.created_by.clone(),
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Name:         {}", self.name)?;
+        writeln!(f, "Info hash:    {}", self.info_hash)?;
+        writeln!(f, "              {}", self.info_hash_base32)?;
+        writeln!(
+            f,
+            "Size:         {} ({} bytes)",
+            format_size(self.total_size),
+            self.total_size
+        )?;
+        writeln!(
+            f,
+            "Pieces:       {} of {}",
+            self.piece_count,
+            format_size(self.piece_length as u64)
+        )?;
+        writeln!(
+            f,
+            "Private:      {}",
+            if self.private { "yes" } else { "no" }
+        )?;
+        if let Some(date) = self.creation_date {
+            writeln!(f, "Created:      {}", format_date(date))?;
+        }
+        if let Some(created_by) = &self.created_by {
+            writeln!(f, "Created by:   {}", created_by)?;
+        }
+        if let Some(comment) = &self.comment {
+            writeln!(f, "Comment:      {}", comment)?;
+        }
+
+        writeln!(f, "\nFiles ({}):", self.files.len())?;
+        for file in &self.files {
+            writeln!(f, "  {:>10}  {}", format_size(file.size), file.path)?;
+        }
+        if !self.trackers.is_empty() {
+            writeln!(f, "\nTrackers:")?;
+            for (tier, trackers) in self.trackers.iter().enumerate() {
+                for tracker in trackers {
+                    writeln!(f, "  tier {}  {}", tier, tracker)?;
+                }
+            }
+        }
+        for (title, urls) in [
+            ("Web seeds", &self.web_seeds),
+            ("HTTP seeds", &self.http_seeds),
+            ("DHT nodes", &self.nodes),
+        ] {
+            if !urls.is_empty() {
+                writeln!(f, "\n{}:", title)?;
+                for url in urls {
+                    writeln!(f, "  {}", url)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// RFC 4648 base32, without padding as in magnet links
+fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer = 0_u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    encoded
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
+
+/// Formats a Unix timestamp as a UTC date
+fn format_date(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86_400);
+    let seconds = timestamp.rem_euclid(86_400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{base32, format_date, Summary};
+    use crate::parse_torrent::{parse_torrent, parse_torrent_bytes, single_file_torrent, UrlList};
+
+    #[test]
+    fn it_summarizes_a_torrent() {
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(format_date(1691692385), "2023-08-10 18:33:05 UTC");
+
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent").unwrap();
+        let summary = Summary::new(&torrent).unwrap();
+        assert_eq!(summary.info_hash.len(), 40);
+        assert_eq!(summary.info_hash_base32.len(), 32);
+        assert_eq!(summary.trackers[0], ["https://torrent.ubuntu.com/announce"]);
+        assert_eq!(summary.files.len(), 1);
+        assert!(summary.to_string().contains("tier 0"));
+
+        let mut torrent = single_file_torrent(b"data", 4);
+        torrent.url_list = Some(UrlList::One("http://seed/".to_string()));
+        let torrent = parse_torrent_bytes(&serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+        assert_eq!(Summary::new(&torrent).unwrap().web_seeds, ["http://seed/"]);
+    }
+}
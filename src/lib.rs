@@ -0,0 +1,18 @@
+pub mod bitfield;
+pub mod dht;
+pub mod disk;
+pub mod download;
+pub mod extension;
+pub mod hash_check;
+pub mod info;
+pub mod messages;
+pub mod parse_torrent;
+pub mod peers;
+pub mod pex;
+pub mod resume;
+pub mod serve;
+pub mod settings;
+pub mod storage;
+pub mod stream;
+pub mod tracker;
+pub mod web_seed;
@@ -1,28 +1,148 @@
-mod messages;
-mod parse_torrent;
-mod peers;
-mod tracker;
-use crate::download::Download;
 use anyhow::Result;
-use parse_torrent::parse_torrent;
-use peers::ConnectionManager;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use furia::dht::{Dht, DhtSettings, DhtState};
+use furia::download::{Download, PieceOrder};
+use furia::info::Summary;
+use furia::parse_torrent::parse_torrent;
+use furia::peers::ConnectionManager;
+use furia::serve::serve;
+use furia::settings::Settings;
+use furia::tracker::{get_info_hash, request_tracker, LISTEN_PORT};
 use rand::{distributions::Alphanumeric, Rng};
-use std::env;
-use tracker::request_tracker;
-use futures::future;  
-use tracing_subscriber;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
 
-pub mod download;
+#[derive(Parser)]
+#[command(version, about = "A simple BitTorrent client")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Torrent to download
+    torrent: Option<PathBuf>,
+    #[command(flatten)]
+    paths: Paths,
+    #[command(flatten)]
+    network: Network,
+}
+
+/// Where a torrent's content is written
+#[derive(Args)]
+struct Paths {
+    /// Directory the content is saved to
+    #[arg(long, default_value = ".")]
+    save_path: PathBuf,
+    /// Directory the content is downloaded to, moved to the save path once complete
+    #[arg(long)]
+    incomplete_path: Option<PathBuf>,
+}
+
+/// How peers are found
+#[derive(Args)]
+struct Network {
+    /// Only find peers through trackers and web seeds
+    #[arg(long)]
+    no_dht: bool,
+    /// UDP port of the DHT node
+    #[arg(long, default_value_t = DhtSettings::default().port)]
+    dht_port: u16,
+    /// File the DHT node id and routing table are kept in between runs
+    #[arg(long, default_value = "furia.dht")]
+    dht_state: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download a torrent while serving its files over HTTP, with Range support
+    Serve {
+        torrent: PathBuf,
+        /// Address the HTTP server listens on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        #[command(flatten)]
+        paths: Paths,
+        #[command(flatten)]
+        network: Network,
+    },
+    /// Print what a torrent file describes, without downloading it
+    Info {
+        torrent: PathBuf,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Usage: {} <torrent file>", args[0]);
-        return Ok(());
-    }
+    let cli = Cli::parse();
     tracing_subscriber::fmt::init();
-    let torrent = parse_torrent(&args[1]);
+    match (cli.command, cli.torrent) {
+        (
+            Some(Command::Serve {
+                torrent,
+                listen,
+                paths,
+                network,
+            }),
+            _,
+        ) => {
+            let settings = Settings {
+                piece_order: PieceOrder::Sequential,
+                first_and_last_pieces_first: true,
+                save_path: paths.save_path,
+                incomplete_path: paths.incomplete_path,
+                ..Settings::default()
+            };
+            let (connection_manager, dht) = start(&torrent, settings, &network).await?;
+            let listener = TcpListener::bind(listen).await?;
+            tokio::spawn(serve(listener, connection_manager.handle()));
+            let (result, interrupted) = run_until_interrupted(connection_manager).await;
+            if let Err(e) = result {
+                warn!(?e, "Download stopped");
+            }
+            if !interrupted {
+                info!("Still serving, press Ctrl-C to stop");
+                if let Err(e) = tokio::signal::ctrl_c().await {
+                    warn!(?e, "Failed to wait for Ctrl-C");
+                }
+            }
+            save_dht(dht, &network).await;
+        }
+        (Some(Command::Info { torrent, json }), _) => {
+            let summary = Summary::new(&parse_torrent(&torrent.to_string_lossy())?)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else {
+                print!("{}", summary);
+            }
+        }
+        (None, Some(torrent)) => {
+            let settings = Settings {
+                save_path: cli.paths.save_path,
+                incomplete_path: cli.paths.incomplete_path,
+                ..Settings::default()
+            };
+            let (connection_manager, dht) = start(&torrent, settings, &cli.network).await?;
+            let (result, _) = run_until_interrupted(connection_manager).await;
+            save_dht(dht, &cli.network).await;
+            result?;
+        }
+        (None, None) => Cli::command().print_help()?,
+    }
+    Ok(())
+}
+
+/// Loads a torrent, checks or resumes its data and connects to the peers of its
+/// tracker, then keeps looking for peers on the DHT
+async fn start(
+    torrent: &Path,
+    settings: Settings,
+    network: &Network,
+) -> Result<(ConnectionManager, Option<Dht>)> {
+    let torrent = parse_torrent(&torrent.to_string_lossy())?;
 
     let peer_id = format!(
         "-FU0001-{}",
@@ -32,17 +152,90 @@ async fn main() -> Result<()> {
             .map(char::from)
             .collect::<String>()
     );
-    let tracker_response = request_tracker(&torrent, &peer_id).await?;
     let download = Download::from(&torrent);
+    let mut connection_manager =
+        ConnectionManager::new(torrent, download, &peer_id, settings).await?;
 
-    let mut connection_manager = ConnectionManager::new(torrent, download, &peer_id).await;
+    // Web seeds can download the torrent on their own, so a failing tracker is not fatal
+    match request_tracker(
+        connection_manager.torrent(),
+        &peer_id,
+        connection_manager.tracker_state(),
+        connection_manager.bytes_left().await,
+    )
+    .await
+    {
+        Ok(tracker_response) => {
+            connection_manager.update_tracker(&tracker_response);
+            for peer in tracker_response.peers.into_iter() {
+                connection_manager.add_peer(peer)?;
+            }
+        }
+        Err(e) => warn!(?e, "Tracker request failed"),
+    }
 
-    for peer in tracker_response.peers.into_iter() {
-        connection_manager.add_peer(peer)?;
+    // Private torrents only get peers from their tracker (BEP 27)
+    if network.no_dht || connection_manager.torrent().is_private() {
+        return Ok((connection_manager, None));
     }
+    let dht_settings = DhtSettings {
+        port: network.dht_port,
+        state_path: network.dht_state.clone(),
+        ..DhtSettings::default()
+    };
+    let state = match DhtState::load(&dht_settings.state_path).await {
+        Ok(state) => Some(state),
+        Err(e) => {
+            info!(?e, "Starting a new DHT node");
+            None
+        }
+    };
+    let address = SocketAddr::from(([0, 0, 0, 0], dht_settings.port));
+    let dht = Dht::restore(address, state.as_ref()).await?;
+    let torrent = connection_manager.torrent();
+    let info_hash = get_info_hash(&torrent.info)?;
+    // Nodes from the last run come first, routers are only needed when none of them answer
+    let mut nodes = state.as_ref().map(DhtState::nodes).unwrap_or_default();
+    nodes.extend(
+        torrent
+            .nodes
+            .iter()
+            .flatten()
+            .map(|node| format!("{}:{}", node.0, node.1)),
+    );
+    let peers = connection_manager.peer_source();
+    let routers = dht_settings.routers;
+    let node = dht.clone();
+    tokio::spawn(async move {
+        if node.bootstrap(&nodes).await == 0 {
+            node.bootstrap(&routers).await;
+        }
+        node.find_peers(info_hash, LISTEN_PORT, peers).await;
+    });
+    Ok((connection_manager, Some(dht)))
+}
 
-    let tasks = connection_manager.handle_messages().await?;
-    future::join_all(tasks).await;
+/// Runs the coordinator until it is done, or until Ctrl-C stops it after it saved its
+/// state. Returns whether Ctrl-C was pressed.
+async fn run_until_interrupted(connection_manager: ConnectionManager) -> (Result<()>, bool) {
+    let handle = connection_manager.handle();
+    let coordinator = connection_manager.handle_messages();
+    tokio::pin!(coordinator);
+    tokio::select! {
+        result = &mut coordinator => (result, false),
+        _ = tokio::signal::ctrl_c() => {
+            handle.stop();
+            (coordinator.await, true)
+        }
+    }
+}
 
-    Ok(())
+/// Keeps the DHT node id and routing table for the next run
+async fn save_dht(dht: Option<Dht>, network: &Network) {
+    let Some(dht) = dht else {
+        return;
+    };
+    if let Err(e) = dht.save_state().save(&network.dht_state).await {
+        warn!(?e, "Failed to save the DHT state");
+    }
 }
@@ -2,11 +2,79 @@ use crate::{
     download::Download,
     parse_torrent::{bitfield_size, TorrentFile},
 };
+use anyhow::Result;
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{collections::BTreeMap, ops::RangeInclusive};
 
 pub struct Message {}
 
 pub const BLOCK_BYTES: u32 = 16384;
+/// Extended message id of the extension handshake (BEP 10)
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// Longest extended message accepted, leaving room for metadata pieces
+const MAX_EXTENDED_LENGTH: u32 = 1 << 17;
+/// Longest message of a type we don't know, which is skipped
+const MAX_UNKNOWN_LENGTH: u32 = 1 << 10;
+
+/// Lengths a frame of type `message_id` may have, its id included, in a torrent of
+/// `piece_count` pieces
+pub fn frame_lengths(message_id: u8, piece_count: usize) -> RangeInclusive<u32> {
+    let bitfield_length = piece_count.div_ceil(8) as u32 + 1;
+    match MessageType::from_u8(message_id) {
+        Some(
+            MessageType::Choke
+            | MessageType::Unchoke
+            | MessageType::Interested
+            | MessageType::NotInterested
+            | MessageType::HaveAll
+            | MessageType::HaveNone,
+        ) => 1..=1,
+        Some(MessageType::Have | MessageType::SuggestPiece | MessageType::AllowedFast) => 5..=5,
+        Some(MessageType::Request | MessageType::Cancel | MessageType::RejectRequest) => 13..=13,
+        Some(MessageType::Port) => 3..=3,
+        Some(MessageType::Bitfield) => bitfield_length..=bitfield_length,
+        Some(MessageType::Piece) => 9..=9 + BLOCK_BYTES,
+        Some(MessageType::Extended) => 2..=MAX_EXTENDED_LENGTH,
+        Some(MessageType::KeepAlive) | None => 1..=MAX_UNKNOWN_LENGTH,
+    }
+}
+
+/// Payload of the extension handshake, telling which extensions a peer supports
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message ids the sender wants them sent with,
+    /// 0 meaning disabled
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// TCP port the sender listens on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<i64>,
+    /// Compact IP address the receiver connects from, as the sender sees it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    /// Number of requests the sender queues before dropping new ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// Size of the bencoded info dictionary, for peers fetching metadata (BEP 9)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+}
 
 #[repr(u8)]
 #[derive(FromPrimitive)]
@@ -22,7 +90,12 @@ pub enum MessageType {
     Cancel,
     Port,
     KeepAlive,
-    Extended = 20
+    SuggestPiece = 13,
+    HaveAll,
+    HaveNone,
+    RejectRequest,
+    AllowedFast,
+    Extended = 20,
 }
 
 impl Message {
@@ -32,7 +105,7 @@ impl Message {
     }
 
     pub fn choke() -> Vec<u8> {
-        let len = 0_u32.to_be_bytes();
+        let len = 1_u32.to_be_bytes();
         let mut message = Vec::from(len);
         message.push(MessageType::Choke as u8);
         message
@@ -60,23 +133,23 @@ impl Message {
     }
 
     pub fn bitfield(torrent: &TorrentFile, download: &Download) -> Vec<u8> {
-        let bitfield_size = bitfield_size(&torrent);
+        let bitfield_size = bitfield_size(torrent);
+        let bitfield = download.bitfield();
 
-        let len = bitfield_size as u32 + 1;
+        let len = bitfield_size + 1;
         let mut message = Vec::from(len.to_be_bytes());
         message.push(MessageType::Bitfield as u8);
-        message.extend_from_slice(&vec![0_u8; len as usize]);
-        dbg!(hex::encode(&message));
+        message.extend_from_slice(bitfield.as_bytes());
         message
     }
 
-    pub fn request(piece_index: u32, piece_offset: u32) -> Vec<u8> {
+    pub fn request(piece_index: u32, piece_offset: u32, length: u32) -> Vec<u8> {
         let len = 13_u32.to_be_bytes();
         let mut message = Vec::from(len);
         message.push(MessageType::Request as u8);
         message.extend_from_slice(&piece_index.to_be_bytes());
-        message.extend_from_slice(&(piece_offset * BLOCK_BYTES as u32).to_be_bytes());
-        message.extend_from_slice(&BLOCK_BYTES.to_be_bytes());
+        message.extend_from_slice(&(piece_offset * BLOCK_BYTES).to_be_bytes());
+        message.extend_from_slice(&length.to_be_bytes());
         message
     }
 
@@ -88,31 +161,155 @@ impl Message {
         message
     }
 
-    pub fn piece(piece_index: u8, piece_offset: u8, block: Vec<u8>) {
-        todo!();
+    pub fn piece(piece_index: u32, piece_offset: u32, block: &[u8]) -> Vec<u8> {
+        let len = (9 + block.len() as u32).to_be_bytes();
+        let mut message = Vec::from(len);
+        message.push(MessageType::Piece as u8);
+        message.extend_from_slice(&piece_index.to_be_bytes());
+        message.extend_from_slice(&(piece_offset * BLOCK_BYTES).to_be_bytes());
+        message.extend_from_slice(block);
+        message
     }
 
-    pub fn cancel(piece_index: u8, piece_offset: u8) {
-        todo!();
+    pub fn cancel(piece_index: u32, piece_offset: u32, length: u32) -> Vec<u8> {
+        let len = 13_u32.to_be_bytes();
+        let mut message = Vec::from(len);
+        message.push(MessageType::Cancel as u8);
+        message.extend_from_slice(&piece_index.to_be_bytes());
+        message.extend_from_slice(&(piece_offset * BLOCK_BYTES).to_be_bytes());
+        message.extend_from_slice(&length.to_be_bytes());
+        message
     }
 
-    pub fn port(port: u8) {
+    /// Fast extension (BEP 6): advises the peer to download a piece we can serve cheaply
+    pub fn suggest_piece(piece_index: u32) -> Vec<u8> {
+        let len = 5_u32.to_be_bytes();
+        let mut message = Vec::from(len);
+        message.push(MessageType::SuggestPiece as u8);
+        message.extend_from_slice(&piece_index.to_be_bytes());
+        message
+    }
+
+    /// Fast extension (BEP 6): replaces a bitfield with every piece set
+    pub fn have_all() -> Vec<u8> {
+        let len = 1_u32.to_be_bytes();
+        let mut message = Vec::from(len);
+        message.push(MessageType::HaveAll as u8);
+        message
+    }
+
+    /// Fast extension (BEP 6): replaces an empty bitfield
+    pub fn have_none() -> Vec<u8> {
+        let len = 1_u32.to_be_bytes();
+        let mut message = Vec::from(len);
+        message.push(MessageType::HaveNone as u8);
+        message
+    }
+
+    /// Fast extension (BEP 6): refuses a request, echoing its byte offset as received
+    pub fn reject_request(piece_index: u32, offset: u32, length: u32) -> Vec<u8> {
+        let len = 13_u32.to_be_bytes();
+        let mut message = Vec::from(len);
+        message.push(MessageType::RejectRequest as u8);
+        message.extend_from_slice(&piece_index.to_be_bytes());
+        message.extend_from_slice(&offset.to_be_bytes());
+        message.extend_from_slice(&length.to_be_bytes());
+        message
+    }
+
+    /// Fast extension (BEP 6): lets the peer request a piece even while we choke it
+    pub fn allowed_fast(piece_index: u32) -> Vec<u8> {
+        let len = 5_u32.to_be_bytes();
+        let mut message = Vec::from(len);
+        message.push(MessageType::AllowedFast as u8);
+        message.extend_from_slice(&piece_index.to_be_bytes());
+        message
+    }
+
+    /// A message of an extension, `extension_id` being the id the peer gave it in its handshake
+    pub fn extended(extension_id: u8, payload: &[u8]) -> Vec<u8> {
+        let len = (2 + payload.len() as u32).to_be_bytes();
+        let mut message = Vec::from(len);
+        message.push(MessageType::Extended as u8);
+        message.push(extension_id);
+        message.extend_from_slice(payload);
+        message
+    }
+
+    pub fn port(port: u16) -> Vec<u8> {
         let len = 3_u32.to_be_bytes();
         let mut message = Vec::from(len);
-        message.push(9_u8);
-        todo!();
+        message.push(MessageType::Port as u8);
+        message.extend_from_slice(&port.to_be_bytes());
+        message
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Message;
+    use super::{frame_lengths, Message, MessageType, BLOCK_BYTES};
+    use crate::{download::Download, parse_torrent::parse_torrent};
 
     #[test]
     fn request_message() {
         assert_eq!(
-            Message::request(0, 0),
+            Message::request(0, 0, BLOCK_BYTES),
             vec![0, 0, 0, 13, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0]
         );
+        assert_eq!(
+            Message::request(8138, 3, 14336),
+            vec![0, 0, 0, 13, 6, 0, 0, 31, 202, 0, 0, 192, 0, 0, 0, 56, 0]
+        );
+    }
+
+    #[test]
+    fn cancel_message() {
+        assert_eq!(
+            Message::cancel(1, 2, BLOCK_BYTES),
+            vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 128, 0, 0, 0, 64, 0]
+        );
+    }
+
+    #[test]
+    fn fast_extension_messages() {
+        assert_eq!(Message::have_all(), vec![0, 0, 0, 1, 14]);
+        assert_eq!(Message::have_none(), vec![0, 0, 0, 1, 15]);
+        assert_eq!(
+            Message::suggest_piece(258),
+            vec![0, 0, 0, 5, 13, 0, 0, 1, 2]
+        );
+        assert_eq!(
+            Message::reject_request(1, BLOCK_BYTES, BLOCK_BYTES),
+            vec![0, 0, 0, 13, 16, 0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 64, 0]
+        );
+        assert_eq!(Message::allowed_fast(7), vec![0, 0, 0, 5, 17, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn it_bounds_frame_lengths_by_type() {
+        assert!(frame_lengths(MessageType::Have as u8, 10).contains(&5));
+        assert!(!frame_lengths(MessageType::Have as u8, 10).contains(&3));
+        assert_eq!(frame_lengths(MessageType::Bitfield as u8, 10), 3..=3);
+        assert!(!frame_lengths(MessageType::Piece as u8, 10).contains(&u32::MAX));
+        assert!(frame_lengths(MessageType::Piece as u8, 10).contains(&(9 + BLOCK_BYTES)));
+        assert!(!frame_lengths(200, 10).contains(&(1 << 20)));
+    }
+
+    #[test]
+    fn bitfield_message() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent").unwrap();
+        let mut download = Download::from(&torrent);
+        for piece_index in [0, 9, download.piece_count() - 1] {
+            download.set_verified(piece_index);
+        }
+
+        let message = Message::bitfield(&torrent, &download);
+        let bitfield_size = download.piece_count().div_ceil(8);
+        assert_eq!(message.len(), 5 + bitfield_size);
+        assert_eq!(&message[..4], &(bitfield_size as u32 + 1).to_be_bytes());
+        assert_eq!(message[4], 5);
+        assert_eq!(&message[5..7], &[0b1000_0000, 0b0100_0000]);
+        let spare_bits = bitfield_size * 8 - download.piece_count();
+        assert_eq!(message[message.len() - 1], 1 << spare_bits);
     }
 }
@@ -1,18 +1,28 @@
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use std::{fmt, io};
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Node(String, i64);
+/// DHT node as (host, port)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Node(pub String, pub i64);
+
+/// `url-list` of BEP 19, a single URL or a list of them
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct File {
-    path: Vec<String>,
-    length: i64,
+    pub path: Vec<String>,
+    pub length: i64,
     #[serde(default)]
-    md5sum: Option<String>,
+    pub md5sum: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Info {
     pub name: String,
     /// concatenation of each piece sha1 hash. Size multiple of 20 bytes
@@ -35,40 +45,240 @@ pub struct Info {
     pub root_hash: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Info {
+    /// Size in bytes of the whole content, summing the files of multi-file torrents
+    pub fn total_length(&self) -> u64 {
+        match (self.length, &self.files) {
+            (Some(length), _) => length as u64,
+            (None, Some(files)) => files.iter().map(|file| file.length as u64).sum(),
+            (None, None) => 0,
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len() / 20
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct TorrentFile {
     pub info: Info,
     #[serde(default)]
     pub announce: String,
     #[serde(default)]
-    nodes: Option<Vec<Node>>,
+    pub nodes: Option<Vec<Node>>,
+    #[serde(default)]
+    pub encoding: Option<String>,
+    /// BEP 17 seeds
     #[serde(default)]
-    encoding: Option<String>,
+    pub httpseeds: Option<Vec<String>>,
+    /// BEP 19 web seeds
     #[serde(default)]
-    httpseeds: Option<Vec<String>>,
+    #[serde(rename = "url-list")]
+    pub url_list: Option<UrlList>,
     #[serde(default)]
     #[serde(rename = "announce-list")]
-    announce_list: Option<Vec<Vec<String>>>,
+    pub announce_list: Option<Vec<Vec<String>>>,
     #[serde(default)]
     #[serde(rename = "creation date")]
-    creation_date: Option<i64>,
+    pub creation_date: Option<i64>,
     #[serde(rename = "comment")]
-    comment: Option<String>,
+    pub comment: Option<String>,
     #[serde(default)]
     #[serde(rename = "created by")]
-    created_by: Option<String>,
+    pub created_by: Option<String>,
+}
+
+impl TorrentFile {
+    /// Tracker tiers from `announce-list`, or `announce` alone when there is none
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if !tiers.is_empty() => tiers.clone(),
+            _ if self.announce.is_empty() => Vec::new(),
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
+
+    /// URLs of the BEP 19 web seeds
+    pub fn web_seeds(&self) -> Vec<String> {
+        match &self.url_list {
+            Some(UrlList::One(url)) if !url.is_empty() => vec![url.clone()],
+            Some(UrlList::Many(urls)) => urls.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+}
+
+/// Why a torrent file was rejected
+#[derive(Debug)]
+pub enum TorrentError {
+    Read(io::Error),
+    Decode(serde_bencode::Error),
+    /// `pieces` is not a list of 20 bytes sha1 hashes
+    PiecesLength(usize),
+    /// `piece length` is zero or negative
+    PieceLength(i64),
+    /// Number of hashes in `pieces` does not match the content length
+    PieceCount {
+        expected: u64,
+        actual: usize,
+    },
+    /// A file, or the single file of the torrent, has a negative length
+    NegativeLength {
+        path: Vec<String>,
+        length: i64,
+    },
+    /// The content is larger than what a file system can hold
+    LengthOverflow,
+    /// Both `length` and `files` are set, so the layout is ambiguous
+    LengthAndFiles,
+    /// Neither `length` nor `files` is set
+    MissingLength,
+}
+
+impl fmt::Display for TorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(e) => write!(f, "Unable to read torrent file: {}", e),
+            Self::Decode(e) => write!(f, "Unable to decode torrent file: {}", e),
+            Self::PiecesLength(length) => {
+                write!(f, "Pieces are {} bytes, not a multiple of 20", length)
+            }
+            Self::PieceLength(length) => write!(f, "Invalid piece length {}", length),
+            Self::PieceCount { expected, actual } => write!(
+                f,
+                "Torrent has {} pieces but its length needs {}",
+                actual, expected
+            ),
+            Self::NegativeLength { path, length } => {
+                write!(f, "File {:?} has a negative length {}", path, length)
+            }
+            Self::LengthOverflow => write!(f, "Total length of the files overflows"),
+            Self::LengthAndFiles => write!(f, "Torrent has both a length and a file list"),
+            Self::MissingLength => write!(f, "Torrent has neither a length nor a file list"),
+        }
+    }
+}
+
+impl std::error::Error for TorrentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Read(e) => Some(e),
+            Self::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Info {
+    /// Checks the invariants the rest of furia relies on
+    pub fn validate(&self) -> Result<(), TorrentError> {
+        if !self.pieces.len().is_multiple_of(20) {
+            return Err(TorrentError::PiecesLength(self.pieces.len()));
+        }
+        if self.piece_length <= 0 {
+            return Err(TorrentError::PieceLength(self.piece_length));
+        }
+        let total_length = match (self.length, &self.files) {
+            (Some(_), Some(_)) => return Err(TorrentError::LengthAndFiles),
+            (None, None) => return Err(TorrentError::MissingLength),
+            (Some(length), None) => {
+                if length < 0 {
+                    return Err(TorrentError::NegativeLength {
+                        path: vec![self.name.clone()],
+                        length,
+                    });
+                }
+                length as u64
+            }
+            (None, Some(files)) => {
+                let mut total_length = 0_u64;
+                for file in files {
+                    if file.length < 0 {
+                        return Err(TorrentError::NegativeLength {
+                            path: file.path.clone(),
+                            length: file.length,
+                        });
+                    }
+                    total_length = total_length
+                        .checked_add(file.length as u64)
+                        .ok_or(TorrentError::LengthOverflow)?;
+                }
+                total_length
+            }
+        };
+        let expected = total_length.div_ceil(self.piece_length as u64);
+        if expected != self.piece_count() as u64 {
+            return Err(TorrentError::PieceCount {
+                expected,
+                actual: self.piece_count(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Decodes and validates a torrent
+pub fn parse_torrent_bytes(bytes: &[u8]) -> Result<TorrentFile, TorrentError> {
+    let torrent: TorrentFile = serde_bencode::from_bytes(bytes).map_err(TorrentError::Decode)?;
+    torrent.info.validate()?;
+    Ok(torrent)
 }
 
-pub fn parse_torrent(file_path: &str) -> TorrentFile {
-    let torrent_file = std::fs::read(file_path).expect("Unable to read file");
-    serde_bencode::from_bytes(&torrent_file).expect("Unable to parse torrent file")
+pub fn parse_torrent(file_path: &str) -> Result<TorrentFile, TorrentError> {
+    let bytes = std::fs::read(file_path).map_err(TorrentError::Read)?;
+    parse_torrent_bytes(&bytes)
 }
 
 pub fn bitfield_size(torrent: &TorrentFile) -> u32 {
-    let number_of_pieces = ((torrent.info.length.unwrap() + torrent.info.piece_length - 1)
-        / torrent.info.piece_length) as usize;
-    ((number_of_pieces + 7) / 8) as u32
+    torrent.info.piece_count().div_ceil(8) as u32
+}
+
+/// Builds an in-memory torrent describing `data` as a single file
+#[cfg(test)]
+pub(crate) fn single_file_torrent(data: &[u8], piece_length: usize) -> TorrentFile {
+    use sha1::{Digest, Sha1};
+
+    let pieces = data
+        .chunks(piece_length)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect::<Vec<u8>>();
+    TorrentFile {
+        info: Info {
+            name: "test".to_string(),
+            pieces: ByteBuf::from(pieces),
+            piece_length: piece_length as i64,
+            length: Some(data.len() as i64),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
 
+/// A torrent of `data` split into top-level files of the given names and lengths
+#[cfg(test)]
+pub(crate) fn multi_file_torrent(
+    data: &[u8],
+    piece_length: usize,
+    files: &[(&str, i64)],
+) -> TorrentFile {
+    let mut torrent = single_file_torrent(data, piece_length);
+    torrent.info.length = None;
+    torrent.info.files = Some(
+        files
+            .iter()
+            .map(|(name, length)| File {
+                path: vec![name.to_string()],
+                length: *length,
+                md5sum: None,
+            })
+            .collect(),
+    );
+    torrent
 }
 
 #[cfg(test)]
@@ -77,13 +287,48 @@ mod test {
 
     #[test]
     fn it_parses_a_torrent_file() {
-        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
-        assert_eq!(
-            "https://torrent.ubuntu.com/announce",
-            torrent.announce
-        );
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent").unwrap();
+        assert_eq!("https://torrent.ubuntu.com/announce", torrent.announce);
         assert_eq!(Some(1691692385), torrent.creation_date);
         assert_eq!("ubuntu-22.04.3-live-server-amd64.iso", torrent.info.name);
         assert_eq!(262144, torrent.info.piece_length);
     }
+
+    #[test]
+    fn it_rejects_invalid_metainfo() {
+        let check = |change: fn(&mut Info)| {
+            let mut torrent = single_file_torrent(&[0; 25], 10);
+            change(&mut torrent.info);
+            let bytes = serde_bencode::to_bytes(&torrent).unwrap();
+            parse_torrent_bytes(&bytes).err()
+        };
+        assert!(check(|_| {}).is_none());
+        assert!(matches!(
+            check(|info| info.pieces.truncate(19)),
+            Some(TorrentError::PiecesLength(19))
+        ));
+        assert!(matches!(
+            check(|info| info.piece_length = 0),
+            Some(TorrentError::PieceLength(0))
+        ));
+        assert!(matches!(
+            check(|info| info.length = Some(31)),
+            Some(TorrentError::PieceCount {
+                expected: 4,
+                actual: 3
+            })
+        ));
+        assert!(matches!(
+            check(|info| info.length = Some(-1)),
+            Some(TorrentError::NegativeLength { length: -1, .. })
+        ));
+        assert!(matches!(
+            check(|info| info.files = Some(Vec::new())),
+            Some(TorrentError::LengthAndFiles)
+        ));
+        assert!(matches!(
+            parse_torrent_bytes(b"d4:infoe"),
+            Err(TorrentError::Decode(_))
+        ));
+    }
 }
@@ -1,307 +1,1970 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::future::try_join_all;
 use num_traits::FromPrimitive;
+use serde_bytes::ByteBuf;
 use std::{
-    io::{SeekFrom, Write},
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, SocketAddrV4},
+    ops::Range,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
-    sync::Mutex,
+    sync::{mpsc, oneshot, watch, Mutex, Notify},
+    time::{self, Instant},
 };
-use tracing::{info, span, Level, error, warn};
+use tracing::{error, info, span, warn, Instrument, Level};
 
 use crate::{
-    download::{Block, Download},
-    messages::{Message, MessageType},
+    bitfield::Bitfield,
+    disk::{DiskEvent, DiskIo},
+    download::{Download, Priority},
+    extension::{ExtensionHandler, ExtensionRegistry},
+    hash_check::{check_pieces, CheckProgress},
+    messages::{
+        frame_lengths, ExtendedHandshake, Message, MessageType, BLOCK_BYTES, EXTENDED_HANDSHAKE_ID,
+    },
     parse_torrent::TorrentFile,
-    tracker::{get_info_hash, Peer},
+    pex::{self, PexMessage, PexState, FLAG_OUTGOING, FLAG_SEED, PEX_INTERVAL},
+    resume::{PartialPiece, ResumeData},
+    settings::Settings,
+    storage::{sanitize_component, FileLayout, FsStorage, Storage},
+    stream::TorrentHandle,
+    tracker::{
+        get_encoded_info_hash, get_info_hash, Peer, TrackerResponse, TrackerState, LISTEN_PORT,
+    },
+    web_seed::{WebSeed, WebSeedClient},
 };
 
+/// Time without outgoing traffic after which a keep-alive is sent
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// Number of encoded messages that can wait in a peer's outbound queue
+const OUTBOUND_QUEUE_SIZE: usize = 64;
+/// Number of events that can wait for the coordinator before peer readers block
+const EVENT_QUEUE_SIZE: usize = 256;
+/// How often the resume file is refreshed while downloading
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Connections above which discovered peers are ignored
+const MAX_PEER_CONNECTIONS: usize = 50;
+/// How long a web seed rests after a failure, multiplied by its consecutive failures
+const WEB_SEED_BACKOFF: Duration = Duration::from_secs(15);
+/// Consecutive failures after which a web seed is dropped
+const MAX_WEB_SEED_FAILURES: u32 = 5;
+/// Reserved handshake bit announcing the extension protocol (BEP 10)
+const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+/// Reserved handshake bit announcing the fast extension (BEP 6)
+const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+/// Most pieces a peer may allow us to request while choked, or suggest, that are remembered
+const MAX_FAST_PIECES: usize = 32;
+/// Requests a peer may queue with us, advertised as `reqq`
+const REQUEST_QUEUE_SIZE: i64 = 250;
+
 pub enum PeerStatus {
     Chocked,
     Interested,
 }
 
+/// Control messages sent from the coordinator to a peer's writer task
+#[derive(Debug)]
+pub enum PeerCommand {
+    /// Withdraw a block request previously sent to the peer
+    Cancel {
+        piece_index: u32,
+        piece_offset: u32,
+        length: u32,
+    },
+    Choke,
+    Unchoke,
+    /// Close the connection and stop both peer tasks
+    Disconnect,
+}
+
+/// Coordinator-side state of a web seed, which has every piece and fetches one at a time
+struct WebSeedConnection {
+    seed: WebSeed,
+    /// Whether a fetch is running
+    requested: bool,
+    /// Consecutive failed fetches
+    failures: u32,
+    /// When the seed may be asked again after a failure
+    retry_at: Instant,
+}
+
+/// Blocks of a piece fetched by a web seed
+struct WebSeedResponse {
+    piece_index: usize,
+    blocks: Range<usize>,
+    result: Result<Vec<u8>>,
+}
+
+/// Events sent from a peer's tasks to the coordinator
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// Handshake completed, the peer can receive messages
+    Connected {
+        /// Reserved bytes of the peer's handshake, flagging the extensions it supports
+        reserved: [u8; 8],
+    },
+    /// A full message frame, without the length prefix
+    Message(Vec<u8>),
+    Disconnected,
+}
+
+/// Requests sent to the running coordinator
+pub(crate) enum Command {
+    /// Hashes the data on disk again, answering once the check is over
+    Recheck {
+        progress: watch::Sender<CheckProgress>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Outcome of a check, sent back by the task running it
+    Checked {
+        result: Result<Bitfield>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Disconnects every peer, writes what was downloaded and saves the resume data
+    Stop,
+}
+
 pub struct ConnectionManager {
     torrent: Arc<TorrentFile>,
     download: Arc<Mutex<Download>>,
-    peer_connections: Vec<PeerConnection>,
+    peer_connections: HashMap<usize, PeerConnection>,
+    next_connection_id: usize,
     peer_id: String,
-    file: Arc<Mutex<File>>,
+    info_hash: [u8; 20],
+    settings: Settings,
+    disk: Arc<DiskIo>,
+    disk_events: mpsc::UnboundedReceiver<DiskEvent>,
+    /// Where resume data is saved, if it is kept at all
+    resume_path: Option<PathBuf>,
+    tracker_state: TrackerState,
+    events_tx: mpsc::Sender<(usize, PeerEvent)>,
+    events_rx: mpsc::Receiver<(usize, PeerEvent)>,
+    /// Bumped every time a piece is verified, for streams waiting on pieces
+    verified_tx: watch::Sender<u64>,
+    /// Notified by streams that set piece deadlines, and when web seeds may retry
+    wake: Arc<Notify>,
+    commands_tx: mpsc::UnboundedSender<Command>,
+    commands_rx: mpsc::UnboundedReceiver<Command>,
+    /// A recheck is running, so no piece is requested until it is over
+    checking: bool,
+    web_seeds: HashMap<usize, WebSeedConnection>,
+    web_seed_client: Arc<WebSeedClient>,
+    web_seed_tx: mpsc::UnboundedSender<(usize, WebSeedResponse)>,
+    web_seed_rx: mpsc::UnboundedReceiver<(usize, WebSeedResponse)>,
+    /// Bitfield of a source having every piece, such as a web seed
+    all_pieces: Bitfield,
+    /// Peers discovered while running, e.g. on the DHT
+    peer_source: Option<mpsc::UnboundedReceiver<Peer>>,
+    /// Extensions plugged into the extension protocol besides the built-in ones
+    extensions: ExtensionRegistry,
+    /// Size of the bencoded info dictionary
+    metadata_size: usize,
 }
 
 impl ConnectionManager {
-    pub async fn new(torrent: TorrentFile, mut download: Download, peer_id: &str) -> Self {
-        let filename = &torrent.info.name.clone();
-        let mut file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(filename)
-            .await
-            .unwrap();
+    /// Downloads to `settings.incomplete_path`, or straight to `settings.save_path`, keeping
+    /// resume data in the download directory. Content already moved to the save path
+    /// is seeded from there once its resume data or a hash check shows it is whole.
+    pub async fn new(
+        torrent: TorrentFile,
+        download: Download,
+        peer_id: &str,
+        settings: Settings,
+    ) -> Result<Self> {
+        let layout = FileLayout::from_torrent(&torrent);
+        let download_path = settings
+            .incomplete_path
+            .as_ref()
+            .unwrap_or(&settings.save_path);
+        let resume_path =
+            ResumeData::path(&download_path.join(sanitize_component(&torrent.info.name)));
+        let mut loaded = None;
+        let mut storage: Arc<dyn Storage> = Arc::new(FsStorage::new(download_path, layout.clone()));
+        if settings.incomplete_path.is_some()
+            && layout
+                .files
+                .iter()
+                .any(|file| settings.save_path.join(&file.path).exists())
+        {
+            // A stale file of the same name must not receive the download
+            let completed: Arc<dyn Storage> = Arc::new(FsStorage::new(&settings.save_path, layout));
+            let info_hash = get_info_hash(&torrent.info)?;
+            let (download, tracker_state) = load(
+                download.clone(),
+                &completed,
+                &info_hash,
+                Some(&resume_path),
+                &settings,
+            )
+            .await?;
+            if download.has_all_pieces() {
+                storage = completed;
+                loaded = Some((download, tracker_state));
+            } else {
+                info!("Content in the save path is incomplete, downloading again");
+            }
+        }
+        let download = match loaded {
+            Some(loaded) => Loaded::Done(loaded),
+            None => Loaded::Pending(download),
+        };
+        Self::open(
+            torrent,
+            download,
+            peer_id,
+            settings,
+            storage,
+            Some(resume_path),
+        )
+        .await
+    }
 
-        let mut piece = vec![0; torrent.info.piece_length as usize];
-        let mut piece_checking = 0;
+    /// Downloads into a custom storage backend, without resume data
+    pub async fn with_storage(
+        torrent: TorrentFile,
+        download: Download,
+        peer_id: &str,
+        settings: Settings,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self> {
+        Self::open(
+            torrent,
+            Loaded::Pending(download),
+            peer_id,
+            settings,
+            storage,
+            None,
+        )
+        .await
+    }
 
-        while let Ok(_) = file.read_exact(&mut piece).await {
-            download.set_piece(&piece, piece_checking);
-            piece_checking += 1;
-        }
+    async fn open(
+        torrent: TorrentFile,
+        download: Loaded,
+        peer_id: &str,
+        settings: Settings,
+        storage: Arc<dyn Storage>,
+        resume_path: Option<PathBuf>,
+    ) -> Result<Self> {
+        let info_hash = get_info_hash(&torrent.info)?;
+        let metadata_size = serde_bencode::to_bytes(&torrent.info)?.len();
+        let (download, tracker_state) = match download {
+            Loaded::Done(loaded) => loaded,
+            Loaded::Pending(download) => {
+                load(
+                    download,
+                    &storage,
+                    &info_hash,
+                    resume_path.as_deref(),
+                    &settings,
+                )
+                .await?
+            }
+        };
+        storage
+            .allocate(settings.allocation)
+            .map_err(|e| anyhow!("Failed to allocate storage: {}", e))?;
 
-        let file = Arc::new(Mutex::new(file));
+        let (events_tx, events_rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+        let (disk, disk_events) =
+            DiskIo::new(storage, torrent.info.piece_length as u64, &settings.disk);
+        let web_seed_client = WebSeedClient::new(&torrent, get_encoded_info_hash(&torrent.info)?)?;
+        let (web_seed_tx, web_seed_rx) = mpsc::unbounded_channel();
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let mut all_pieces = Bitfield::new(download.piece_count());
+        for piece_index in 0..download.piece_count() {
+            all_pieces.set(piece_index);
+        }
+        let web_seeds = WebSeed::from_torrent(&torrent);
 
-        Self {
+        let mut manager = Self {
             torrent: Arc::new(torrent),
             download: Arc::new(Mutex::new(download)),
-            peer_connections: Vec::new(),
+            peer_connections: HashMap::new(),
+            next_connection_id: 0,
             peer_id: peer_id.to_owned(),
-            file,
+            info_hash,
+            settings,
+            disk: Arc::new(disk),
+            disk_events,
+            resume_path,
+            tracker_state,
+            events_tx,
+            events_rx,
+            verified_tx: watch::channel(0).0,
+            wake: Arc::new(Notify::new()),
+            commands_tx,
+            commands_rx,
+            checking: false,
+            web_seeds: HashMap::new(),
+            web_seed_client: Arc::new(web_seed_client),
+            web_seed_tx,
+            web_seed_rx,
+            all_pieces,
+            peer_source: None,
+            extensions: ExtensionRegistry::default(),
+            metadata_size,
+        };
+        for seed in web_seeds {
+            manager.add_web_seed(seed);
         }
+        Ok(manager)
+    }
+
+    /// Downloads from a web seed as well as from peers
+    pub fn add_web_seed(&mut self, seed: WebSeed) {
+        info!(url = seed.url, "Adding web seed");
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+        self.web_seeds.insert(
+            id,
+            WebSeedConnection {
+                seed,
+                requested: false,
+                failures: 0,
+                retry_at: Instant::now(),
+            },
+        );
     }
 
+    /// Spawns the reader and writer tasks for a peer and registers it with the coordinator
     pub fn add_peer(&mut self, peer: Peer) -> Result<()> {
-        let peer_connection = PeerConnection::new(peer)?;
-        self.peer_connections.push(peer_connection);
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+
+        let (outbound_tx, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let (control_tx, control_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let pieces = self.torrent.info.pieces.len() / 20;
+        let span = span!(Level::INFO, "peer", ip = peer.ip);
+        tokio::spawn(
+            run_peer(
+                id,
+                peer.clone(),
+                PeerContext {
+                    info_hash: self.info_hash,
+                    peer_id: self.peer_id.clone(),
+                    piece_count: pieces,
+                },
+                self.events_tx.clone(),
+                outbound_rx,
+                control_rx,
+            )
+            .instrument(span),
+        );
+
+        self.peer_connections.insert(
+            id,
+            PeerConnection::new(peer, pieces, outbound_tx, control_tx),
+        );
         Ok(())
     }
 
-    pub async fn handle_messages(self) -> Result<Vec<tokio::task::JoinHandle<()>>> {
-        let torrent = self.torrent.clone();
-        let download = self.download.clone();
-        let peer_id = self.peer_id.clone();
-        let info_hash = get_info_hash(&torrent.info)?;
-        let file = self.file.clone();
-        info!("Number of peers: {}", &self.peer_connections.len());
-        let mut tasks = Vec::new();
-        for mut peer_connection in self.peer_connections {
-            info!("Connecting to peer");
-            let download = download.clone();
-            let torrent = torrent.clone();
-            let peer_id = peer_id.clone();
-            let file = file.clone();
-            let span = span!(Level::INFO, "peer", ip = peer_connection.peer.ip);
-            let task = tokio::spawn(async move {
-                let _guard = span.enter();
-                if let Err(e) = peer_connection.connect().await {
-                    warn!(?e, "Failed to connect to peer");
+    /// Handles the messages of an extension, advertised to peers connecting from now on.
+    /// Returns the message id peers send the extension's messages with.
+    pub fn register_extension(&mut self, handler: Arc<dyn ExtensionHandler>) -> Result<u8> {
+        self.extensions.register(handler)
+    }
+
+    /// Channel to hand over peers found while the coordinator runs. The coordinator
+    /// keeps waiting for peers until every sender is dropped.
+    pub fn peer_source(&mut self) -> mpsc::UnboundedSender<Peer> {
+        let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+        self.peer_source = Some(peers_rx);
+        peers_tx
+    }
+
+    /// Connects to a discovered peer unless it is known already or we have enough peers
+    fn add_discovered_peer(&mut self, peer: Peer) -> Result<()> {
+        let known = self
+            .peer_connections
+            .values()
+            .any(|connection| connection.peer.ip == peer.ip && connection.peer.port == peer.port);
+        if known || self.peer_connections.len() >= MAX_PEER_CONNECTIONS {
+            return Ok(());
+        }
+        self.add_peer(peer)
+    }
+
+    pub fn torrent(&self) -> &TorrentFile {
+        &self.torrent
+    }
+
+    /// Handle to read the torrent's files while it downloads
+    pub fn handle(&self) -> TorrentHandle {
+        TorrentHandle::new(
+            self.download.clone(),
+            self.disk.clone(),
+            self.verified_tx.subscribe(),
+            self.wake.clone(),
+            self.commands_tx.clone(),
+        )
+    }
+
+    pub fn tracker_state(&self) -> &TrackerState {
+        &self.tracker_state
+    }
+
+    pub fn update_tracker(&mut self, response: &TrackerResponse) {
+        self.tracker_state.update(response);
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Recheck { progress, reply } => {
+                if self.checking {
+                    let _ = reply.send(Err(anyhow!("A check is already running")));
                     return;
                 }
-                if let Err(e) = peer_connection.handshake(&info_hash, &peer_id).await {
-                    warn!(?e, "Failed to handshake to peer");
+                if let Err(e) = self.disk.flush().await {
+                    let _ = reply.send(Err(e.into()));
                     return;
                 }
+                self.disk.clear_cache();
+                info!("Checking existing data");
+                self.checking = true;
+                let download = self.download.lock().await.clone();
+                let storage = self.disk.storage().clone();
+                let settings = self.settings.hash_check.clone();
+                let commands = self.commands_tx.clone();
+                tokio::spawn(async move {
+                    let result = check_pieces(storage, &download, &settings, &progress).await;
+                    let _ = commands.send(Command::Checked { result, reply });
+                });
+            }
+            Command::Checked { result, reply } => {
+                self.checking = false;
+                let result = match result {
+                    Ok(verified) => {
+                        self.download.lock().await.set_checked(&verified);
+                        self.verified_tx.send_modify(|verified| *verified += 1);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                };
+                self.update_interest().await;
+                self.request_idle_peers().await;
+                let _ = reply.send(result);
+            }
+            Command::Stop => {}
+        }
+    }
 
-                // if let Err(e) = peer_connection.bitfield(&torrent, &download) {
-                //     dbg!("Failed to handshake to peer: {}", e);
-                //     return;
-                // }
+    /// Changes which files are downloaded and in which order, once the queued writes are done
+    pub async fn set_file_priority(&mut self, file_index: usize, priority: Priority) -> Result<()> {
+        let file_count = self.download.lock().await.file_count();
+        if file_index >= file_count {
+            return Err(anyhow!(
+                "No file {} in a torrent of {} files",
+                file_index,
+                file_count
+            ));
+        }
+        self.disk.flush().await?;
+        self.disk
+            .storage()
+            .set_file_wanted(file_index, priority != Priority::Skip)?;
+        let download = self.download.clone();
+        let mut download = download.lock().await;
+        download.set_file_priority(file_index, priority);
+        drop(download);
+        self.update_interest().await;
+        self.request_idle_peers().await;
+        Ok(())
+    }
 
-                if let Err(e) = peer_connection.interested().await {
-                    info!("Failed to send interest message to peer: {}", e);
-                    return;
+    /// Relocates the torrent's files under `directory` once the queued writes are done
+    pub async fn move_storage(&self, directory: &Path) -> Result<()> {
+        self.disk
+            .move_storage(directory)
+            .await
+            .map_err(|e| anyhow!("Failed to move storage to {}: {}", directory.display(), e))
+    }
+
+    /// Moves the content out of the incomplete downloads directory
+    async fn move_to_save_path(&self) -> Result<()> {
+        if self.settings.incomplete_path.is_some() {
+            info!("Moving to {}", self.settings.save_path.display());
+            self.move_storage(&self.settings.save_path).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn bytes_left(&self) -> u64 {
+        self.download.lock().await.bytes_left()
+    }
+
+    /// Runs the coordinator until the download completes or every peer and web seed is gone
+    pub async fn handle_messages(mut self) -> Result<()> {
+        info!(
+            "Number of peers: {}, web seeds: {}",
+            self.peer_connections.len(),
+            self.web_seeds.len()
+        );
+        let mut save_resume =
+            time::interval_at(Instant::now() + RESUME_SAVE_INTERVAL, RESUME_SAVE_INTERVAL);
+        let mut send_pex = time::interval_at(Instant::now() + PEX_INTERVAL, PEX_INTERVAL);
+        let mut failure = None;
+        self.request_web_seeds().await;
+        loop {
+            if self.download.lock().await.is_complete() {
+                info!("Download complete");
+                break;
+            }
+            if self.peer_connections.is_empty()
+                && self.web_seeds.is_empty()
+                && self.peer_source.is_none()
+            {
+                warn!("No peers left");
+                break;
+            }
+            let (id, event) = tokio::select! {
+                event = self.events_rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = self.wake.notified() => {
+                    self.update_interest().await;
+                    self.request_idle_peers().await;
+                    continue;
+                }
+                Some(event) = self.disk_events.recv() => {
+                    if let Err(e) = self.handle_disk_event(event).await {
+                        failure = Some(e);
+                        break;
+                    }
+                    continue;
+                }
+                peer = next_peer(&mut self.peer_source) => {
+                    match peer {
+                        Some(peer) => self.add_discovered_peer(peer)?,
+                        None => self.peer_source = None,
+                    }
+                    continue;
+                }
+                Some(command) = self.commands_rx.recv() => {
+                    if let Command::Stop = command {
+                        info!("Stopping");
+                        break;
+                    }
+                    self.handle_command(command).await;
+                    continue;
+                }
+                Some((id, response)) = self.web_seed_rx.recv() => {
+                    self.handle_web_seed_response(id, response).await;
+                    continue;
+                }
+                _ = save_resume.tick() => {
+                    self.save_resume_data().await;
+                    continue;
+                }
+                _ = send_pex.tick() => {
+                    self.send_pex();
+                    continue;
                 }
+            };
+            match event {
+                PeerEvent::Connected { reserved } => {
+                    if let Some(peer_connection) = self.peer_connections.get_mut(&id) {
+                        peer_connection.connected = true;
+                        let (byte, bit) = FAST_EXTENSION_BIT;
+                        peer_connection.fast = reserved[byte] & bit != 0;
+                        let download = self.download.lock().await;
+                        peer_connection.bitfield(&self.torrent, &download);
+                        drop(download);
+                    }
+                    let (byte, bit) = EXTENSION_PROTOCOL_BIT;
+                    if let Some(peer_connection) = self.peer_connections.get(&id) {
+                        if reserved[byte] & bit != 0 {
+                            let handshake = self.extended_handshake(&peer_connection.peer);
+                            peer_connection.extended_handshake(&handshake);
+                        }
+                    }
+                }
+                PeerEvent::Message(message) => self.handle_message(id, message).await?,
+                PeerEvent::Disconnected => self.remove_peer(id).await,
+            }
+        }
 
-                loop {
-                    let mut len = Vec::new();
-                    if let Some(connection) = peer_connection.connection.as_mut() {
-                        match connection.take(4).read_to_end(&mut len).await {
-                            Ok(_) => {
-                                if len.len() != 4 {
-                                    info!("Failed to read data from peer");
-                                    return;
-                                }
-                                let length = u32::from_be_bytes(len.try_into().unwrap());
-                                let mut message = vec![0; length as usize];
+        for (_, peer_connection) in self.peer_connections.drain() {
+            peer_connection.disconnect();
+        }
+        if let Err(e) = self.disk.flush().await {
+            error!(?e, "Failed to flush written pieces");
+        }
+        while let Ok(event) = self.disk_events.try_recv() {
+            if let Err(e) = self.handle_disk_event(event).await {
+                error!(?e, "Failed to write pieces");
+            }
+        }
+        if failure.is_none() && self.download.lock().await.has_all_pieces() {
+            if let Err(e) = self.move_to_save_path().await {
+                failure = Some(e);
+            }
+        }
+        self.save_resume_data().await;
+        failure.map_or(Ok(()), Err)
+    }
 
-                                connection.read_exact(&mut message).await.unwrap();
-                                if length == 0 {
-                                    peer_connection.keep_alive().await.unwrap();
-                                    continue;
-                                }
-                                let message_id = message[0];
-                                match MessageType::from_u8(message_id) {
-                                    Some(MessageType::Choke) => {
-                                        info!("Choke");
-                                        peer_connection.am_status = Some(PeerStatus::Chocked);
-                                    }
-                                    Some(MessageType::Unchoke) => {
-                                        info!("Unchoke");
-                                        peer_connection.am_status = Some(PeerStatus::Interested);
-                                        let mut download = download.lock().await;
-                                        if let Some((piece, block)) = download.find_first_block() {
-                                            download.pieces[piece as usize].content
-                                                [block as usize] = Block::Downloading;
-                                            drop(download);
-                                            peer_connection
-                                                .request(piece as u32, block as u32)
-                                                .await
-                                                .unwrap();
-                                        } else {
-                                            info!("Download complete");
-                                            break;
-                                        }
-                                    }
-                                    Some(MessageType::Have) => {
-                                        info!("Have");
-                                    }
-                                    Some(MessageType::Bitfield) => {
-                                        info!("Bitfield");
-                                        peer_connection.bitfield = message[1..].to_vec();
-                                        peer_connection.interested().await.unwrap();
-                                    }
-                                    Some(MessageType::Piece) => {
-                                        let piece_index =
-                                            u32::from_be_bytes(message[1..5].try_into().unwrap());
-                                        let piece_offset =
-                                            u32::from_be_bytes(message[5..9].try_into().unwrap());
-                                        let block = &message[9..];
-
-                                        let mut download = download.lock().await;
-                                        if let Some(data) = download.set_block(
-                                            &block,
-                                            piece_index as usize,
-                                            piece_offset as usize,
-                                        ) {
-                                            let mut file = file.lock().await;
-                                            file.seek(SeekFrom::Start(
-                                                (piece_index as i64 * torrent.info.piece_length)
-                                                    as u64,
-                                            ))
-                                            .await
-                                            .unwrap();
-                                            file.write_all(&data).await.unwrap();
-                                            file.flush().await.unwrap();
-                                            info!("Piece {} downloaded", &piece_index);
-                                        }
-
-                                        if let Some((piece, block)) = download.find_first_block() {
-                                            download.pieces[piece as usize].content
-                                                [block as usize] = Block::Downloading;
-                                            drop(download);
-                                            peer_connection
-                                                .request(piece as u32, block as u32)
-                                                .await
-                                                .unwrap();
-                                        } else {
-                                            info!("Download complete");
-                                            break;
-                                        }
-                                    }
-                                    Some(MessageType::Extended) => {
-                                        info!("Extended");
-                                    }
-                                    _ => {
-                                        info!("Unknown message, {}", &message_id);
-                                        dbg!(String::from_utf8_lossy(&message));
-                                    }
+    /// Writes partially downloaded pieces to disk and records the download state next to it
+    async fn save_resume_data(&mut self) {
+        if let Err(e) = self.try_save_resume_data().await {
+            warn!(?e, "Failed to save resume data");
+        }
+    }
+
+    async fn try_save_resume_data(&mut self) -> Result<()> {
+        let Some(resume_path) = self.resume_path.clone() else {
+            return Ok(());
+        };
+        let download = self.download.lock().await;
+        let mut partial = Vec::new();
+        let mut writes = Vec::new();
+        for (piece_index, received, data) in download.partial_pieces() {
+            writes.push(
+                self.disk
+                    .write_partial(download.piece_offset(piece_index), data.to_vec()),
+            );
+            partial.push(PartialPiece {
+                piece: piece_index as u32,
+                blocks: ByteBuf::from(received.as_bytes()),
+            });
+        }
+        drop(download);
+        try_join_all(writes).await?;
+        self.disk.flush().await?;
+        // Pieces whose write failed go back to missing before the bitfield is saved
+        while let Ok(event) = self.disk_events.try_recv() {
+            self.handle_disk_event(event).await?;
+        }
+
+        let download = self.download.lock().await;
+        let resume_data = ResumeData {
+            info_hash: ByteBuf::from(self.info_hash),
+            pieces: ByteBuf::from(download.bitfield().as_bytes()),
+            partial,
+            files: self.disk.storage().file_states()?,
+            tracker: self.tracker_state.clone(),
+        };
+        resume_data.save(&resume_path).await
+    }
+
+    /// Updates the peer's state from one of its messages. A peer sending a malformed
+    /// message, or one that does not fit the torrent or our requests, is disconnected.
+    async fn handle_message(&mut self, id: usize, message: Vec<u8>) -> Result<()> {
+        let Some(peer_connection) = self.peer_connections.get_mut(&id) else {
+            return Ok(());
+        };
+        let Some(&message_id) = message.first() else {
+            self.disconnect_peer(id, "empty message").await;
+            return Ok(());
+        };
+        let piece_count = peer_connection.bitfield.len();
+        if !frame_lengths(message_id, piece_count).contains(&(message.len() as u32)) {
+            let reason = format!("message {} of {} bytes", message_id, message.len());
+            self.disconnect_peer(id, &reason).await;
+            return Ok(());
+        }
+        // Every field below is in bounds once the frame length is checked
+        let field = |index: usize| {
+            let start = 1 + index * 4;
+            u32::from_be_bytes(message[start..start + 4].try_into().unwrap())
+        };
+        match MessageType::from_u8(message_id) {
+            Some(MessageType::Choke) => {
+                info!("Choke");
+                peer_connection.am_status = Some(PeerStatus::Chocked);
+                // Under the fast extension, requests stay pending until rejected or served
+                if !peer_connection.fast {
+                    if let Some((piece, block)) = peer_connection.requested.take() {
+                        let mut download = self.download.lock().await;
+                        download.reclaim_block(piece as usize, block as usize);
+                    }
+                }
+            }
+            Some(MessageType::Unchoke) => {
+                info!("Unchoke");
+                peer_connection.am_status = Some(PeerStatus::Interested);
+                self.request_next_block(id).await;
+            }
+            Some(MessageType::Interested) => {
+                peer_connection.peer_status = Some(PeerStatus::Interested);
+            }
+            Some(MessageType::NotInterested) => {
+                peer_connection.peer_status = None;
+            }
+            Some(MessageType::Have | MessageType::SuggestPiece | MessageType::AllowedFast)
+                if field(0) as usize >= piece_count =>
+            {
+                let reason = format!("piece {} out of range", field(0));
+                self.disconnect_peer(id, &reason).await;
+            }
+            Some(MessageType::Have) => {
+                let piece_index = field(0);
+                info!("Have {}", piece_index);
+                let mut download = self.download.lock().await;
+                if !peer_connection.bitfield.has(piece_index as usize) {
+                    peer_connection.bitfield.set(piece_index as usize);
+                    download.add_have(piece_index as usize);
+                }
+                peer_connection.update_interest(&download);
+            }
+            Some(MessageType::Bitfield) => {
+                info!("Bitfield");
+                match Bitfield::from_bytes(&message[1..], piece_count) {
+                    Ok(bitfield) => self.replace_bitfield(id, bitfield).await,
+                    Err(_) => self.disconnect_peer(id, "invalid bitfield").await,
+                }
+            }
+            Some(
+                MessageType::HaveAll
+                | MessageType::HaveNone
+                | MessageType::SuggestPiece
+                | MessageType::AllowedFast
+                | MessageType::RejectRequest,
+            ) if !peer_connection.fast => {
+                self.disconnect_peer(id, "fast extension message without the fast extension")
+                    .await;
+            }
+            Some(MessageType::HaveAll) => {
+                info!("Have all");
+                self.replace_bitfield(id, self.all_pieces.clone()).await;
+            }
+            Some(MessageType::HaveNone) => {
+                info!("Have none");
+                self.replace_bitfield(id, Bitfield::new(piece_count)).await;
+            }
+            Some(MessageType::SuggestPiece | MessageType::AllowedFast) => {
+                let piece_index = field(0);
+                let pieces = if message_id == MessageType::SuggestPiece as u8 {
+                    &mut peer_connection.suggested
+                } else {
+                    &mut peer_connection.allowed_fast
+                };
+                if !pieces.contains(&piece_index) {
+                    if pieces.len() == MAX_FAST_PIECES {
+                        pieces.pop_front();
+                    }
+                    pieces.push_back(piece_index);
+                }
+                if peer_connection.requested.is_none() {
+                    self.request_next_block(id).await;
+                }
+            }
+            Some(MessageType::Request | MessageType::Cancel) => {
+                let (piece_index, offset, length) = (field(0), field(1), field(2));
+                let valid = self.download.lock().await.is_valid_block(
+                    piece_index as usize,
+                    offset as usize,
+                    length as usize,
+                );
+                if !valid {
+                    let reason = format!(
+                        "request for {} bytes at {} of piece {}",
+                        length, offset, piece_index
+                    );
+                    self.disconnect_peer(id, &reason).await;
+                } else if message_id == MessageType::Request as u8 && peer_connection.fast {
+                    // Pieces are not uploaded yet, but fast peers are owed an answer to every request
+                    peer_connection.send(Message::reject_request(piece_index, offset, length));
+                }
+            }
+            Some(MessageType::RejectRequest) => {
+                let Some((piece, block)) = peer_connection.take_request(field(0), field(1)) else {
+                    self.disconnect_peer(id, "reject for a block we did not request")
+                        .await;
+                    return Ok(());
+                };
+                info!("Request for piece {} rejected", piece);
+                self.download
+                    .lock()
+                    .await
+                    .reclaim_block(piece as usize, block as usize);
+                self.request_next_block(id).await;
+            }
+            Some(MessageType::Piece) => {
+                let (piece_index, piece_offset) = (field(0), field(1));
+                let block = &message[9..];
+                let Some((_, block_index)) =
+                    peer_connection.take_request(piece_index, piece_offset)
+                else {
+                    let reason = format!(
+                        "unrequested block at {} of piece {}",
+                        piece_offset, piece_index
+                    );
+                    self.disconnect_peer(id, &reason).await;
+                    return Ok(());
+                };
+
+                let download = self.download.clone();
+                let mut download = download.lock().await;
+                let expected = download.block_size(piece_index as usize, block_index as usize);
+                if block.len() != expected {
+                    download.reclaim_block(piece_index as usize, block_index as usize);
+                    drop(download);
+                    let reason = format!("block of {} bytes instead of {}", block.len(), expected);
+                    self.disconnect_peer(id, &reason).await;
+                    return Ok(());
+                }
+                if let Some(data) =
+                    download.set_block(block, piece_index as usize, piece_offset as usize)
+                {
+                    self.piece_verified(&download, piece_index as usize, data);
+                }
+                drop(download);
+                self.request_next_block(id).await;
+            }
+            Some(MessageType::Extended) => {
+                self.handle_extended(id, message[1], &message[2..]).await?;
+            }
+            _ => {
+                info!("Unknown message, {}", &message_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops a peer that broke the protocol, saying how
+    async fn disconnect_peer(&mut self, id: usize, reason: &str) {
+        if let Some(peer_connection) = self.peer_connections.get(&id) {
+            warn!(ip = peer_connection.peer.ip, reason, "Disconnecting peer");
+        }
+        self.remove_peer(id).await;
+    }
+
+    /// Replaces the pieces a peer announced, after a bitfield, have all or have none
+    async fn replace_bitfield(&mut self, id: usize, bitfield: Bitfield) {
+        let Some(peer_connection) = self.peer_connections.get_mut(&id) else {
+            return;
+        };
+        let mut download = self.download.lock().await;
+        download.remove_availability(&peer_connection.bitfield);
+        download.add_availability(&bitfield);
+        peer_connection.bitfield = bitfield;
+        peer_connection.update_interest(&download);
+    }
+
+    /// Our extension handshake: the extensions we support and what we know about the peer
+    fn extended_handshake(&self, peer: &Peer) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            v: Some(format!("furia {}", env!("CARGO_PKG_VERSION"))),
+            p: Some(LISTEN_PORT as i64),
+            yourip: peer.ip.parse::<IpAddr>().ok().map(|ip| match ip {
+                IpAddr::V4(ip) => ByteBuf::from(ip.octets().to_vec()),
+                IpAddr::V6(ip) => ByteBuf::from(ip.octets().to_vec()),
+            }),
+            reqq: Some(REQUEST_QUEUE_SIZE),
+            metadata_size: Some(self.metadata_size as i64),
+            ..ExtendedHandshake::default()
+        };
+        if self.pex_enabled() {
+            handshake
+                .m
+                .insert(pex::EXTENSION_NAME.to_string(), pex::EXTENSION_ID as i64);
+        }
+        self.extensions.extend_handshake(&mut handshake);
+        handshake
+    }
+
+    /// Records the extensions a peer supports, feeds the peers it exchanges to the pool
+    /// and hands the messages of registered extensions to their handler
+    async fn handle_extended(&mut self, id: usize, extension_id: u8, payload: &[u8]) -> Result<()> {
+        let pex_enabled = self.pex_enabled();
+        let Some(peer_connection) = self.peer_connections.get_mut(&id) else {
+            return Ok(());
+        };
+        match extension_id {
+            EXTENDED_HANDSHAKE_ID => match ExtendedHandshake::from_bytes(payload) {
+                Ok(handshake) => {
+                    info!(
+                        client = handshake.v,
+                        yourip = ?handshake.yourip.as_deref().map(hex::encode),
+                        "Extension handshake"
+                    );
+                    peer_connection.extensions = handshake
+                        .m
+                        .iter()
+                        .filter_map(|(name, id)| Some((name.clone(), u8::try_from(*id).ok()?)))
+                        .filter(|(_, id)| *id != 0)
+                        .collect();
+                    self.extensions
+                        .peer_handshake(&peer_connection.peer, &handshake);
+                }
+                Err(e) => {
+                    warn!(?e, "Invalid extension handshake from peer");
+                    self.remove_peer(id).await;
+                }
+            },
+            pex::EXTENSION_ID if pex_enabled => {
+                if !peer_connection.pex.accept_message() {
+                    warn!(
+                        ip = peer_connection.peer.ip,
+                        "Ignoring PEX message sent too early"
+                    );
+                    return Ok(());
+                }
+                match PexMessage::from_bytes(payload) {
+                    Ok(message) => {
+                        let added = message.added_peers();
+                        info!(count = added.len(), "Peers received through PEX");
+                        for (peer, _) in added {
+                            self.add_discovered_peer(peer)?;
+                        }
+                    }
+                    Err(e) => warn!(?e, "Invalid PEX message from peer"),
+                }
+            }
+            _ => {
+                let Some(handler) = self.extensions.get(extension_id) else {
+                    info!("Unknown extended message, {}", extension_id);
+                    return Ok(());
+                };
+                match handler.message(&peer_connection.peer, payload) {
+                    Ok(replies) => {
+                        let reply_id = peer_connection.extensions.get(handler.name()).copied();
+                        for reply in replies {
+                            match reply_id {
+                                Some(reply_id) => {
+                                    peer_connection.send(Message::extended(reply_id, &reply))
                                 }
+                                None => warn!(
+                                    extension = handler.name(),
+                                    "Peer does not accept replies of the extension"
+                                ),
                             }
-                            Err(error) => {
-                                error!("Failed to read data from the peer: {}", error);
-                                break;
-                            }
                         }
                     }
+                    Err(e) => {
+                        warn!(
+                            ?e,
+                            extension = handler.name(),
+                            "Extension failed, disconnecting"
+                        );
+                        self.remove_peer(id).await;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether peer lists are exchanged, which private torrents forbid (BEP 27)
+    fn pex_enabled(&self) -> bool {
+        self.settings.pex && !self.torrent.is_private()
+    }
+
+    /// Tells every peer supporting PEX which peers joined and left since the previous message
+    fn send_pex(&mut self) {
+        if !self.pex_enabled() {
+            return;
+        }
+        let connected = self
+            .peer_connections
+            .values()
+            .filter(|peer_connection| peer_connection.connected)
+            .filter_map(|peer_connection| {
+                let addr = peer_connection.address()?;
+                let mut flags = FLAG_OUTGOING;
+                if peer_connection.bitfield.count() == peer_connection.bitfield.len() {
+                    flags |= FLAG_SEED;
+                }
+                Some((addr, flags))
+            })
+            .collect::<HashMap<_, _>>();
+        for peer_connection in self.peer_connections.values_mut() {
+            let Some(&extension_id) = peer_connection.extensions.get(pex::EXTENSION_NAME) else {
+                continue;
+            };
+            let mut others = connected.clone();
+            if let Some(addr) = peer_connection.address() {
+                others.remove(&addr);
+            }
+            let Some(message) = peer_connection.pex.next_message(&others) else {
+                continue;
+            };
+            match message.to_bytes() {
+                Ok(payload) => peer_connection.send(Message::extended(extension_id, &payload)),
+                Err(e) => warn!(?e, "Failed to encode PEX message"),
+            }
+        }
+    }
+
+    /// Hands a verified piece to the disk threads and wakes streams waiting for it
+    fn piece_verified(&mut self, download: &Download, piece_index: usize, data: Vec<u8>) {
+        info!("Piece {} downloaded", piece_index);
+        self.verified_tx.send_modify(|verified| *verified += 1);
+        self.tracker_state.downloaded += data.len() as u64;
+        let offset = download.piece_offset(piece_index);
+        self.disk.write_piece(piece_index, offset, data);
+    }
+
+    /// Feeds the blocks fetched by a web seed to the download. A seed that fails,
+    /// or sends data that does not match the piece hash, rests for a while and its
+    /// blocks go back to the other sources; it is dropped after repeated failures.
+    async fn handle_web_seed_response(&mut self, id: usize, response: WebSeedResponse) {
+        let Some(web_seed) = self.web_seeds.get_mut(&id) else {
+            return;
+        };
+        web_seed.requested = false;
+        let url = web_seed.seed.url.clone();
+        let WebSeedResponse {
+            piece_index,
+            blocks,
+            result,
+        } = response;
+        let download = self.download.clone();
+        let mut download = download.lock().await;
+        let result = result.and_then(|data| {
+            let mut verified = None;
+            let mut offset = 0;
+            for block_index in blocks.clone() {
+                let size = download.block_size(piece_index, block_index);
+                let block = &data[offset..offset + size];
+                offset += size;
+                if let Some(data) =
+                    download.set_block(block, piece_index, block_index * BLOCK_BYTES as usize)
+                {
+                    verified = Some(data);
+                }
+            }
+            let whole_piece = blocks == (0..download.block_count(piece_index));
+            if verified.is_none() && whole_piece && !download.has_piece(piece_index) {
+                return Err(anyhow!("Piece {} does not match its hash", piece_index));
+            }
+            Ok(verified)
+        });
+        match result {
+            Ok(verified) => {
+                if let Some(web_seed) = self.web_seeds.get_mut(&id) {
+                    web_seed.failures = 0;
+                }
+                if let Some(data) = verified {
+                    self.piece_verified(&download, piece_index, data);
+                }
+            }
+            Err(e) => {
+                warn!(?e, url, "Web seed failed");
+                for block_index in blocks {
+                    download.reclaim_block(piece_index, block_index);
+                }
+                if let Some(web_seed) = self.web_seeds.get_mut(&id) {
+                    web_seed.failures += 1;
+                    if web_seed.failures >= MAX_WEB_SEED_FAILURES {
+                        warn!(url, "Dropping web seed");
+                        self.web_seeds.remove(&id);
+                    } else {
+                        let backoff = WEB_SEED_BACKOFF * web_seed.failures;
+                        web_seed.retry_at = Instant::now() + backoff;
+                        let wake = self.wake.clone();
+                        tokio::spawn(async move {
+                            time::sleep(backoff).await;
+                            wake.notify_one();
+                        });
+                    }
+                }
+            }
+        }
+        drop(download);
+        self.request_idle_peers().await;
+    }
+
+    /// Gives a piece to fetch to every web seed that is not busy or resting
+    async fn request_web_seeds(&mut self) {
+        if self.web_seeds.is_empty() || self.checking || self.disk.is_congested() {
+            return;
+        }
+        let now = Instant::now();
+        let mut download = self.download.lock().await;
+        for (id, web_seed) in self.web_seeds.iter_mut() {
+            if web_seed.requested || web_seed.retry_at > now {
+                continue;
+            }
+            let Some((piece_index, blocks)) = download.reserve_blocks(&self.all_pieces) else {
+                break;
+            };
+            let start = (blocks.start * BLOCK_BYTES as usize) as u64;
+            let end = ((blocks.end - 1) * BLOCK_BYTES as usize
+                + download.block_size(piece_index, blocks.end - 1)) as u64;
+            web_seed.requested = true;
+            let id = *id;
+            let seed = web_seed.seed.clone();
+            let client = self.web_seed_client.clone();
+            let responses = self.web_seed_tx.clone();
+            tokio::spawn(async move {
+                let result = client.fetch(&seed, piece_index, start..end).await;
+                let _ = responses.send((
+                    id,
+                    WebSeedResponse {
+                        piece_index,
+                        blocks,
+                        result,
+                    },
+                ));
+            });
+        }
+    }
+
+    /// Announces pieces once they are on disk, or downloads them again if writing failed.
+    /// A full disk stops the download.
+    async fn handle_disk_event(&mut self, event: DiskEvent) -> Result<()> {
+        match event {
+            DiskEvent::Written {
+                piece_index,
+                data,
+                result,
+            } => {
+                let download = self.download.clone();
+                let mut download = download.lock().await;
+                download.release_buffer(data);
+                match result {
+                    Ok(()) => self.broadcast_have(piece_index as u32, &download),
+                    Err(e) => {
+                        error!(?e, "Failed to write piece {}", piece_index);
+                        download.set_unverified(piece_index);
+                        drop(download);
+                        if e.kind() == io::ErrorKind::StorageFull {
+                            return Err(anyhow!("Disk full while writing piece {}", piece_index));
+                        }
+                        self.update_interest().await;
+                    }
                 }
+            }
+        }
+        self.request_idle_peers().await;
+        Ok(())
+    }
+
+    /// Reserves the next missing block and asks the peer for it, unless the disk is behind.
+    /// Pieces the peer suggested go first; a choking peer is only asked for allowed fast pieces.
+    async fn request_next_block(&mut self, id: usize) {
+        let Some(peer_connection) = self.peer_connections.get_mut(&id) else {
+            return;
+        };
+        if self.checking || self.disk.is_congested() || peer_connection.requested.is_some() {
+            return;
+        }
+        let requestable = peer_connection.requestable();
+        let mut download = self.download.lock().await;
+        let suggested = peer_connection
+            .suggested
+            .iter()
+            .filter(|piece| requestable.has(**piece as usize))
+            .find_map(|piece| {
+                let mut only = Bitfield::new(requestable.len());
+                only.set(*piece as usize);
+                download.reserve_block(&only)
             });
-            tasks.push(task);
+        if let Some((piece, block)) = suggested.or_else(|| download.reserve_block(&requestable)) {
+            let length = download.block_size(piece, block) as u32;
+            peer_connection.requested = Some((piece as u32, block as u32));
+            peer_connection.request(piece as u32, block as u32, length);
+        }
+    }
+
+    async fn update_interest(&mut self) {
+        let download = self.download.lock().await;
+        for peer_connection in self.peer_connections.values_mut() {
+            peer_connection.update_interest(&download);
+        }
+    }
+
+    /// Gives work to unchoked peers left without a request, e.g. after the buffer pool freed up
+    async fn request_idle_peers(&mut self) {
+        let idle = self
+            .peer_connections
+            .iter()
+            .filter(|(_, peer_connection)| {
+                peer_connection.requestable().count() > 0 && peer_connection.requested.is_none()
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in idle {
+            self.request_next_block(id).await;
         }
-        Ok(tasks)
+        self.request_web_seeds().await;
+    }
+
+    /// Announces a completed piece to every peer and drops interest in peers with nothing left to offer
+    fn broadcast_have(&mut self, piece_index: u32, download: &Download) {
+        for peer_connection in self.peer_connections.values_mut() {
+            if !(self.settings.suppress_redundant_have
+                && peer_connection.bitfield.has(piece_index as usize))
+            {
+                peer_connection.have(piece_index);
+            }
+            peer_connection.update_interest(download);
+        }
+    }
+
+    async fn remove_peer(&mut self, id: usize) {
+        if let Some(mut peer_connection) = self.peer_connections.remove(&id) {
+            info!(ip = peer_connection.peer.ip, "Peer disconnected");
+            let mut download = self.download.lock().await;
+            download.remove_availability(&peer_connection.bitfield);
+            if let Some((piece, block)) = peer_connection.requested.take() {
+                download.reclaim_block(piece as usize, block as usize);
+            }
+            drop(download);
+            peer_connection.disconnect();
+        }
+    }
+}
+
+/// Waits for the next discovered peer, forever if there is no peer source
+async fn next_peer(source: &mut Option<mpsc::UnboundedReceiver<Peer>>) -> Option<Peer> {
+    match source {
+        Some(source) => source.recv().await,
+        None => std::future::pending().await,
     }
 }
 
+/// Coordinator-side state of a peer, whose socket is owned by its reader and writer tasks
 pub struct PeerConnection {
     peer: Peer,
     am_status: Option<PeerStatus>,
     peer_status: Option<PeerStatus>,
-    connection: Option<TcpStream>,
-    bitfield: Vec<u8>,
+    /// Pieces the peer announced through `bitfield` and `have`
+    bitfield: Bitfield,
+    /// Whether we told the peer we are interested in its pieces
+    am_interested: bool,
+    /// Block currently requested from the peer, as (piece index, block index)
+    requested: Option<(u32, u32)>,
+    /// Whether the handshake completed
+    connected: bool,
+    /// Whether both sides support the fast extension
+    fast: bool,
+    /// Pieces the peer lets us request while it chokes us
+    allowed_fast: VecDeque<u32>,
+    /// Pieces the peer advised us to download
+    suggested: VecDeque<u32>,
+    /// Extensions the peer supports, with the ids it wants their messages sent with
+    extensions: HashMap<String, u8>,
+    pex: PexState,
+    outbound: mpsc::Sender<Vec<u8>>,
+    control: mpsc::Sender<PeerCommand>,
 }
 
 impl PeerConnection {
-    fn new(peer: Peer) -> Result<Self> {
-        Ok(Self {
+    fn new(
+        peer: Peer,
+        pieces: usize,
+        outbound: mpsc::Sender<Vec<u8>>,
+        control: mpsc::Sender<PeerCommand>,
+    ) -> Self {
+        Self {
             peer,
-            connection: None,
             am_status: None,
             peer_status: None,
-            bitfield: Vec::new(),
-        })
+            bitfield: Bitfield::new(pieces),
+            am_interested: false,
+            requested: None,
+            connected: false,
+            fast: false,
+            allowed_fast: VecDeque::new(),
+            suggested: VecDeque::new(),
+            extensions: HashMap::new(),
+            pex: PexState::default(),
+            outbound,
+            control,
+        }
     }
 
-    async fn connect(&mut self) -> Result<()> {
-        let connection =
-            TcpStream::connect(format!("{}:{}", &self.peer.ip, &self.peer.port)).await?;
-        self.connection = Some(connection);
-        Ok(())
+    /// IPv4 address of the peer, the only kind exchanged over PEX
+    fn address(&self) -> Option<SocketAddrV4> {
+        let port = u16::try_from(self.peer.port).ok()?;
+        Some(SocketAddrV4::new(self.peer.ip.parse().ok()?, port))
     }
 
-    async fn handshake(&mut self, info_hash: &[u8; 20], peer_id: &str) -> Result<()> {
-        let mut concatenated_bytes = Vec::new();
-        Write::write_all(&mut concatenated_bytes, &[19_u8])
-            .expect("Failed to write number of bytes");
-        concatenated_bytes.extend_from_slice("BitTorrent protocol00000000".as_bytes());
-        concatenated_bytes.extend_from_slice(info_hash);
-        concatenated_bytes.extend_from_slice(&peer_id.as_bytes());
-        if let Some(connection) = &mut self.connection {
-            connection.write_all(&concatenated_bytes).await?;
-            let mut len = [0_u8; 1];
-            connection.read_exact(&mut len).await?;
-            let mut message = vec![0; len[0] as usize];
-            connection.read_exact(&mut message).await?;
-            let mut reserved = [0_u8; 8];
-            connection.read_exact(&mut reserved).await?;
-            let mut info_hash = [0_u8; 20];
-            let mut peer_id = [0_u8; 20];
-            connection.read_exact(&mut info_hash).await?;
-            connection.read_exact(&mut peer_id).await?;
-        } else {
-            error!("Failed to establish connection");
+    /// Queues a message for the writer task. A peer that can't keep up with its queue is dropped.
+    fn send(&self, message: Vec<u8>) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.outbound.try_send(message) {
+            warn!(ip = self.peer.ip, "Outbound queue full, disconnecting");
+            self.disconnect();
         }
+    }
 
-        Ok(())
+    pub fn command(&self, command: PeerCommand) {
+        let _ = self.control.try_send(command);
     }
 
-    async fn bitfield(&mut self, torrent: &TorrentFile, download: &Download) -> Result<()> {
-        let message = Message::bitfield(&torrent, &download);
-        if let Some(connection) = &mut self.connection {
-            connection.write_all(&message).await?;
+    pub fn disconnect(&self) {
+        self.command(PeerCommand::Disconnect);
+    }
+
+    /// Advertises our verified pieces. Peers without the fast extension are told nothing
+    /// when we have no pieces yet.
+    pub fn bitfield(&self, torrent: &TorrentFile, download: &Download) {
+        let count = download.bitfield().count();
+        if self.fast && count == download.piece_count() {
+            self.send(Message::have_all());
+        } else if self.fast && count == 0 {
+            self.send(Message::have_none());
+        } else if count > 0 {
+            self.send(Message::bitfield(torrent, download));
         }
-        Ok(())
     }
 
-    async fn keep_alive(&mut self) -> Result<()> {
-        let message = Message::keep_alive();
-        if let Some(connection) = &mut self.connection {
-            connection.write_all(&message).await?;
+    /// Clears the outstanding request if it is for the block at `offset` of `piece_index`,
+    /// returning it as (piece index, block index)
+    fn take_request(&mut self, piece_index: u32, offset: u32) -> Option<(u32, u32)> {
+        let (piece, block) = self.requested?;
+        if piece != piece_index || block.checked_mul(BLOCK_BYTES) != Some(offset) {
+            return None;
         }
-        Ok(())
+        self.requested.take()
     }
 
-    async fn interested(&mut self) -> Result<()> {
-        let message = Message::interested();
-        if let Some(connection) = &mut self.connection {
-            connection.write_all(&message).await?;
+    /// Pieces we may request now: any the peer has once it unchoked us, otherwise its
+    /// allowed fast pieces
+    fn requestable(&self) -> Bitfield {
+        if matches!(self.am_status, Some(PeerStatus::Interested)) {
+            return self.bitfield.clone();
         }
-        Ok(())
+        let mut requestable = Bitfield::new(self.bitfield.len());
+        if self.fast {
+            for piece in &self.allowed_fast {
+                if self.bitfield.has(*piece as usize) {
+                    requestable.set(*piece as usize);
+                }
+            }
+        }
+        requestable
     }
 
-    async fn request(&mut self, piece_index: u32, piece_offset: u32) -> Result<()> {
-        let message = Message::request(piece_index, piece_offset);
-        if let Some(connection) = &mut self.connection {
-            connection.write_all(&message).await?;
+    pub fn interested(&self) {
+        self.send(Message::interested());
+    }
+
+    pub fn not_interested(&self) {
+        self.send(Message::not_interested());
+    }
+
+    /// Sends `interested` or `not interested` when the peer starts or stops having pieces we miss
+    pub fn update_interest(&mut self, download: &Download) {
+        let interested = download.wants(&self.bitfield);
+        if interested != self.am_interested {
+            self.am_interested = interested;
+            if interested {
+                self.interested();
+            } else {
+                self.not_interested();
+            }
         }
-        Ok(())
     }
 
-    async fn have(&mut self, piece_index: u32) -> Result<()> {
-        let message = Message::have(piece_index);
-        if let Some(connection) = &mut self.connection {
-            connection.write_all(&message).await?;
+    pub fn request(&self, piece_index: u32, piece_offset: u32, length: u32) {
+        self.send(Message::request(piece_index, piece_offset, length));
+    }
+
+    pub fn have(&self, piece_index: u32) {
+        self.send(Message::have(piece_index));
+    }
+
+    /// Tells the peer which extensions we support and the ids to send them with
+    pub fn extended_handshake(&self, handshake: &ExtendedHandshake) {
+        match handshake.to_bytes() {
+            Ok(payload) => self.send(Message::extended(EXTENDED_HANDSHAKE_ID, &payload)),
+            Err(e) => warn!(?e, "Failed to encode extension handshake"),
+        }
+    }
+}
+
+/// A download whose pieces on disk are known, or still to be found
+enum Loaded {
+    Done((Download, TrackerState)),
+    Pending(Download),
+}
+
+/// Finds the pieces already in `storage`, from the resume data when it is usable or
+/// by hashing the data otherwise
+async fn load(
+    mut download: Download,
+    storage: &Arc<dyn Storage>,
+    info_hash: &[u8; 20],
+    resume_path: Option<&Path>,
+    settings: &Settings,
+) -> Result<(Download, TrackerState)> {
+    download.set_max_in_flight(settings.max_pieces_in_flight);
+    download.set_piece_order(settings.piece_order);
+    download.set_first_and_last_first(settings.first_and_last_pieces_first);
+    for file_index in 0..download.file_count() {
+        storage.set_file_wanted(
+            file_index,
+            download.file_priority(file_index) != Priority::Skip,
+        )?;
+    }
+    let resumed = match resume_path {
+        Some(resume_path) => resume(resume_path, info_hash, &download, storage.as_ref()).await,
+        None => Err(anyhow!("Resume data disabled")),
+    };
+    match resumed {
+        Ok(resumed) => {
+            info!("Resumed from saved state");
+            Ok(resumed)
+        }
+        Err(e) => {
+            info!(?e, "Resume data not usable, checking existing data");
+            let (progress, _) = watch::channel(CheckProgress::default());
+            let verified =
+                check_pieces(storage.clone(), &download, &settings.hash_check, &progress).await?;
+            download.set_checked(&verified);
+            Ok((download, TrackerState::default()))
+        }
+    }
+}
+
+/// Restores the download state saved by a previous run, if the data on disk was left untouched
+async fn resume(
+    resume_path: &Path,
+    info_hash: &[u8; 20],
+    download: &Download,
+    storage: &dyn Storage,
+) -> Result<(Download, TrackerState)> {
+    let resume_data = ResumeData::load(resume_path).await?;
+    if !resume_data.matches(info_hash, &storage.file_states()?) {
+        return Err(anyhow!("Data changed since the resume file was saved"));
+    }
+
+    let mut download = download.clone();
+    let pieces = Bitfield::from_bytes(&resume_data.pieces, download.piece_count())?;
+    for piece_index in 0..download.piece_count() {
+        if pieces.has(piece_index) {
+            download.set_verified(piece_index);
+        }
+    }
+    for partial in resume_data.partial {
+        let piece_index = partial.piece as usize;
+        if piece_index >= download.piece_count() {
+            return Err(anyhow!("Partial piece {} out of range", piece_index));
+        }
+        let received = Bitfield::from_bytes(&partial.blocks, download.block_count(piece_index))?;
+        let mut data = vec![0; download.piece_size(piece_index)];
+        storage.read_block(download.piece_offset(piece_index), &mut data)?;
+        download.restore_partial(piece_index, received, data);
+    }
+    Ok((download, resume_data.tracker))
+}
+
+/// What a peer's tasks know about the torrent and ourselves
+struct PeerContext {
+    info_hash: [u8; 20],
+    peer_id: String,
+    piece_count: usize,
+}
+
+async fn run_peer(
+    id: usize,
+    peer: Peer,
+    context: PeerContext,
+    events: mpsc::Sender<(usize, PeerEvent)>,
+    outbound: mpsc::Receiver<Vec<u8>>,
+    control: mpsc::Receiver<PeerCommand>,
+) {
+    info!("Connecting to peer");
+    let mut connection = match TcpStream::connect(format!("{}:{}", &peer.ip, &peer.port)).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!(?e, "Failed to connect to peer");
+            let _ = events.send((id, PeerEvent::Disconnected)).await;
+            return;
+        }
+    };
+    let reserved = match handshake(&mut connection, &context.info_hash, &context.peer_id).await {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            warn!(?e, "Failed to handshake to peer");
+            let _ = events.send((id, PeerEvent::Disconnected)).await;
+            return;
+        }
+    };
+
+    let (reader, writer) = connection.into_split();
+    if events
+        .send((id, PeerEvent::Connected { reserved }))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let reader = tokio::spawn(
+        read_messages(id, reader, context.piece_count, events.clone()).in_current_span(),
+    );
+    if let Err(e) = write_messages(writer, outbound, control).await {
+        warn!(?e, "Failed to write to peer");
+    }
+    reader.abort();
+    let _ = events.send((id, PeerEvent::Disconnected)).await;
+}
+
+/// Exchanges handshakes with the peer, returning the reserved bytes it sent
+async fn handshake(
+    connection: &mut TcpStream,
+    info_hash: &[u8; 20],
+    peer_id: &str,
+) -> Result<[u8; 8]> {
+    let mut reserved = [0_u8; 8];
+    for (byte, bit) in [EXTENSION_PROTOCOL_BIT, FAST_EXTENSION_BIT] {
+        reserved[byte] |= bit;
+    }
+    let mut concatenated_bytes = vec![19_u8];
+    concatenated_bytes.extend_from_slice("BitTorrent protocol".as_bytes());
+    concatenated_bytes.extend_from_slice(&reserved);
+    concatenated_bytes.extend_from_slice(info_hash);
+    concatenated_bytes.extend_from_slice(peer_id.as_bytes());
+    connection.write_all(&concatenated_bytes).await?;
+
+    let mut len = [0_u8; 1];
+    connection.read_exact(&mut len).await?;
+    let mut message = vec![0; len[0] as usize];
+    connection.read_exact(&mut message).await?;
+    let mut reserved = [0_u8; 8];
+    connection.read_exact(&mut reserved).await?;
+    let mut info_hash = [0_u8; 20];
+    let mut peer_id = [0_u8; 20];
+    connection.read_exact(&mut info_hash).await?;
+    connection.read_exact(&mut peer_id).await?;
+    Ok(reserved)
+}
+
+/// Forwards every frame received from the peer to the coordinator until the connection
+/// fails, or the peer sends a frame longer than its message type allows
+async fn read_messages<R: AsyncRead + Unpin>(
+    id: usize,
+    mut reader: R,
+    piece_count: usize,
+    events: mpsc::Sender<(usize, PeerEvent)>,
+) {
+    loop {
+        let length = match reader.read_u32().await {
+            Ok(length) => length,
+            Err(e) => {
+                error!("Failed to read data from the peer: {}", e);
+                break;
+            }
+        };
+        if length == 0 {
+            continue;
+        }
+        let message_id = match reader.read_u8().await {
+            Ok(message_id) => message_id,
+            Err(e) => {
+                error!("Failed to read data from the peer: {}", e);
+                break;
+            }
+        };
+        if !frame_lengths(message_id, piece_count).contains(&length) {
+            warn!(
+                message_id,
+                length, "Frame length invalid for its message type, disconnecting"
+            );
+            break;
+        }
+        let mut message = vec![0; length as usize];
+        message[0] = message_id;
+        if let Err(e) = reader.read_exact(&mut message[1..]).await {
+            error!("Failed to read data from the peer: {}", e);
+            break;
+        }
+        if events
+            .send((id, PeerEvent::Message(message)))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    let _ = events.send((id, PeerEvent::Disconnected)).await;
+}
+
+/// Writes queued messages and control commands to the peer, sending keep-alives when idle
+async fn write_messages<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut outbound: mpsc::Receiver<Vec<u8>>,
+    mut control: mpsc::Receiver<PeerCommand>,
+) -> Result<()> {
+    let keep_alive = time::sleep(KEEP_ALIVE_INTERVAL);
+    tokio::pin!(keep_alive);
+    loop {
+        let message = tokio::select! {
+            command = control.recv() => match command {
+                Some(PeerCommand::Cancel { piece_index, piece_offset, length }) => {
+                    Message::cancel(piece_index, piece_offset, length)
+                }
+                Some(PeerCommand::Choke) => Message::choke(),
+                Some(PeerCommand::Unchoke) => Message::unchoke(),
+                Some(PeerCommand::Disconnect) | None => break,
+            },
+            message = outbound.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = &mut keep_alive => Message::keep_alive(),
+        };
+        writer
+            .write_all(&message)
+            .await
+            .map_err(|e| anyhow!("Failed to send message: {}", e))?;
+        keep_alive
+            .as_mut()
+            .reset(Instant::now() + KEEP_ALIVE_INTERVAL);
+    }
+    let _ = writer.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_messages, write_messages, ConnectionManager, PeerCommand, PeerEvent};
+    use crate::{
+        dht::encode_peer,
+        download::Download,
+        extension::ExtensionHandler,
+        hash_check::CheckProgress,
+        messages::{ExtendedHandshake, Message, MessageType, BLOCK_BYTES, EXTENDED_HANDSHAKE_ID},
+        parse_torrent::{multi_file_torrent, single_file_torrent},
+        pex::{self, PexMessage},
+        serve::serve,
+        settings::Settings,
+        storage::{FileLayout, MemoryStorage, Storage},
+        stream::test_handle,
+        tracker::Peer,
+        web_seed::{WebSeed, WebSeedKind},
+    };
+    use serde_bytes::ByteBuf;
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, watch},
+        task::JoinHandle,
+        time,
+    };
+
+    #[tokio::test]
+    async fn it_writes_queued_messages_and_commands() {
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let (outbound_tx, outbound_rx) = mpsc::channel(4);
+        let (control_tx, control_rx) = mpsc::channel(4);
+
+        outbound_tx.send(Message::interested()).await.unwrap();
+        let task = tokio::spawn(write_messages(writer, outbound_rx, control_rx));
+        let mut received = vec![0; 5];
+        reader.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, Message::interested());
+
+        control_tx
+            .send(PeerCommand::Cancel {
+                piece_index: 1,
+                piece_offset: 2,
+                length: BLOCK_BYTES,
+            })
+            .await
+            .unwrap();
+        control_tx.send(PeerCommand::Disconnect).await.unwrap();
+        task.await.unwrap().unwrap();
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, Message::cancel(1, 2, BLOCK_BYTES));
+    }
+
+    #[tokio::test]
+    async fn it_downloads_from_web_seeds() {
+        let data = (0..25_u8).collect::<Vec<u8>>();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, test_handle(&data, &[("a", 12), ("b", 13)])));
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let torrent = multi_file_torrent(&data, 10, &[("a", 12), ("b", 13)]);
+        let download = Download::from(&torrent);
+        let storage = Arc::new(MemoryStorage::new(FileLayout::from_torrent(&torrent)));
+        let mut connection_manager = ConnectionManager::with_storage(
+            torrent,
+            download,
+            "-FU0001-000000000000",
+            Settings::default(),
+            storage.clone(),
+        )
+        .await
+        .unwrap();
+        for address in [unreachable, address] {
+            connection_manager.add_web_seed(WebSeed {
+                url: format!("http://{}/", address),
+                kind: WebSeedKind::UrlList,
+            });
+        }
+        connection_manager.handle_messages().await.unwrap();
+        assert_eq!(storage.data(), data);
+    }
+
+    #[tokio::test]
+    async fn it_seeds_from_the_save_path_only_when_the_content_is_whole() {
+        let root = std::env::temp_dir().join(format!("furia-completed-{}", std::process::id()));
+        let data = (0..25_u8).collect::<Vec<u8>>();
+        let torrent = single_file_torrent(&data, 10);
+        let settings = Settings {
+            save_path: root.join("complete"),
+            incomplete_path: Some(root.join("incomplete")),
+            ..Settings::default()
+        };
+        std::fs::create_dir_all(root.join("complete")).unwrap();
+
+        // A stale file of the same name is not mistaken for the completed download
+        std::fs::write(root.join("complete/test"), [0; 25]).unwrap();
+        let connection_manager = ConnectionManager::new(
+            single_file_torrent(&data, 10),
+            Download::from(&torrent),
+            "-FU0001-000000000000",
+            settings.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(connection_manager.bytes_left().await, 25);
+        assert!(root.join("incomplete/test").exists());
+        drop(connection_manager);
+
+        std::fs::remove_dir_all(root.join("incomplete")).unwrap();
+        std::fs::write(root.join("complete/test"), &data).unwrap();
+        let connection_manager = ConnectionManager::new(
+            torrent,
+            Download::from(&single_file_torrent(&data, 10)),
+            "-FU0001-000000000000",
+            settings,
+        )
+        .await
+        .unwrap();
+        assert_eq!(connection_manager.bytes_left().await, 0);
+        assert!(!root.join("incomplete/test").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_rechecks_while_running() {
+        let data = (0..25_u8).collect::<Vec<u8>>();
+        let torrent = single_file_torrent(&data, 10);
+        let download = Download::from(&torrent);
+        let storage = Arc::new(MemoryStorage::new(FileLayout::from_torrent(&torrent)));
+        let mut connection_manager = ConnectionManager::with_storage(
+            torrent,
+            download,
+            "-FU0001-000000000000",
+            Settings::default(),
+            storage.clone(),
+        )
+        .await
+        .unwrap();
+        let _peers = connection_manager.peer_source();
+        let handle = connection_manager.handle();
+        let coordinator = tokio::spawn(connection_manager.handle_messages());
+
+        storage.write_piece(0, &data).unwrap();
+        let (progress, progress_rx) = watch::channel(CheckProgress::default());
+        handle.recheck(progress).await.unwrap();
+        assert_eq!(progress_rx.borrow().verified, 3);
+        time::timeout(Duration::from_secs(5), coordinator)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(handle
+            .recheck(watch::channel(Default::default()).0)
+            .await
+            .is_err());
+    }
+
+    /// A coordinator downloading a small torrent from a peer played by the test, which
+    /// gets the stream once the handshakes are exchanged
+    async fn connect_test_peer(
+        extensions: Vec<Arc<dyn ExtensionHandler>>,
+    ) -> (JoinHandle<anyhow::Result<()>>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let torrent = single_file_torrent(&[1; 25], 10);
+        let download = Download::from(&torrent);
+        let storage = Arc::new(MemoryStorage::new(FileLayout::from_torrent(&torrent)));
+        let mut connection_manager = ConnectionManager::with_storage(
+            torrent,
+            download,
+            "-FU0001-000000000000",
+            Settings::default(),
+            storage,
+        )
+        .await
+        .unwrap();
+        for extension in extensions {
+            connection_manager.register_extension(extension).unwrap();
+        }
+        connection_manager
+            .add_peer(Peer {
+                peer_id: None,
+                ip: "127.0.0.1".to_string(),
+                port: listener.local_addr().unwrap().port() as i64,
+            })
+            .unwrap();
+        let coordinator = tokio::spawn(connection_manager.handle_messages());
+
+        let (mut connection, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 68];
+        connection.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(handshake[25] & 0x10, 0x10);
+        connection.write_all(&handshake).await.unwrap();
+        (coordinator, connection)
+    }
+
+    /// Reads frames from the coordinator until one of type `message_id` arrives
+    async fn read_message(connection: &mut TcpStream, message_id: MessageType) -> Vec<u8> {
+        let message_id = message_id as u8;
+        loop {
+            let length = connection.read_u32().await.unwrap();
+            let mut message = vec![0; length as usize];
+            connection.read_exact(&mut message).await.unwrap();
+            if message.first() == Some(&message_id) {
+                return message;
+            }
+        }
+    }
+
+    /// Reads frames from the coordinator until an extended message with `extension_id` arrives
+    async fn read_extended(connection: &mut TcpStream, extension_id: u8) -> Vec<u8> {
+        loop {
+            let mut message = read_message(connection, MessageType::Extended).await;
+            if message.get(1) == Some(&extension_id) {
+                return message.split_off(2);
+            }
+        }
+    }
+
+    struct Echo;
+
+    impl ExtensionHandler for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn message(&self, _peer: &Peer, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    #[tokio::test]
+    async fn it_negotiates_extensions_and_routes_their_messages() {
+        let (coordinator, mut connection) = connect_test_peer(vec![Arc::new(Echo)]).await;
+        let handshake = read_extended(&mut connection, EXTENDED_HANDSHAKE_ID).await;
+        let handshake = ExtendedHandshake::from_bytes(&handshake).unwrap();
+        assert_eq!(handshake.m[pex::EXTENSION_NAME], pex::EXTENSION_ID as i64);
+        assert_eq!(handshake.yourip.unwrap().as_slice(), [127, 0, 0, 1]);
+        assert!(handshake.reqq.is_some());
+        assert!(handshake.metadata_size.unwrap() > 0);
+        let echo_id = handshake.m["echo"] as u8;
+
+        let mut extensions = ExtendedHandshake::default();
+        extensions.m.insert("echo".to_string(), 7);
+        for message in [
+            Message::extended(EXTENDED_HANDSHAKE_ID, &extensions.to_bytes().unwrap()),
+            Message::extended(echo_id, b"ping"),
+        ] {
+            connection.write_all(&message).await.unwrap();
+        }
+        assert_eq!(read_extended(&mut connection, 7).await, b"ping");
+        coordinator.abort();
+    }
+
+    #[tokio::test]
+    async fn it_connects_to_peers_received_through_pex() {
+        let exchanged = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (coordinator, mut connection) = connect_test_peer(Vec::new()).await;
+        let mut extensions = ExtendedHandshake::default();
+        extensions.m.insert(pex::EXTENSION_NAME.to_string(), 3);
+        let added = match exchanged.local_addr().unwrap() {
+            SocketAddr::V4(addr) => encode_peer(&addr),
+            _ => unreachable!(),
+        };
+        let message = PexMessage {
+            added: ByteBuf::from(added),
+            ..Default::default()
+        };
+        for message in [
+            Message::extended(EXTENDED_HANDSHAKE_ID, &extensions.to_bytes().unwrap()),
+            Message::extended(pex::EXTENSION_ID, &message.to_bytes().unwrap()),
+        ] {
+            connection.write_all(&message).await.unwrap();
+        }
+
+        time::timeout(Duration::from_secs(5), exchanged.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        coordinator.abort();
+    }
+
+    #[tokio::test]
+    async fn it_requests_allowed_fast_pieces_while_choked() {
+        let (coordinator, mut connection) = connect_test_peer(Vec::new()).await;
+        assert_eq!(
+            read_message(&mut connection, MessageType::HaveNone).await,
+            [MessageType::HaveNone as u8]
+        );
+        for message in [Message::have_all(), Message::allowed_fast(1)] {
+            connection.write_all(&message).await.unwrap();
+        }
+        let request = read_message(&mut connection, MessageType::Request).await;
+        assert_eq!(request[1..9], [0, 0, 0, 1, 0, 0, 0, 0]);
+
+        // A rejected block goes back to the pool and is asked for again
+        connection
+            .write_all(&Message::reject_request(1, 0, 10))
+            .await
+            .unwrap();
+        let request = read_message(&mut connection, MessageType::Request).await;
+        assert_eq!(request[1..5], [0, 0, 0, 1]);
+        coordinator.abort();
+    }
+
+    #[tokio::test]
+    async fn it_drops_frames_longer_than_their_type_allows() {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let (events_tx, mut events_rx) = mpsc::channel(4);
+        let task = tokio::spawn(read_messages(0, reader, 3, events_tx));
+        writer.write_all(&Message::have(1)).await.unwrap();
+        // A have claiming 4 GiB, which must not be allocated
+        writer
+            .write_all(&[0xff, 0xff, 0xff, 0xff, 4])
+            .await
+            .unwrap();
+        task.await.unwrap();
+
+        let Some((0, PeerEvent::Message(message))) = events_rx.recv().await else {
+            panic!("Expected the have message");
+        };
+        assert_eq!(message, Message::have(1)[4..]);
+        assert!(matches!(
+            events_rx.recv().await,
+            Some((0, PeerEvent::Disconnected))
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_disconnects_peers_breaking_the_protocol() {
+        for message in [
+            Message::have(3),
+            Message::piece(0, 0, &[1; 10]),
+            Message::request(0, 0, BLOCK_BYTES),
+        ] {
+            let (coordinator, mut connection) = connect_test_peer(Vec::new()).await;
+            connection.write_all(&message).await.unwrap();
+            let mut received = Vec::new();
+            connection.read_to_end(&mut received).await.unwrap();
+            // The only peer is gone, so the download stops
+            coordinator.await.unwrap().unwrap();
         }
-        Ok(())
     }
 }
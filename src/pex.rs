@@ -0,0 +1,190 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{collections::HashMap, net::SocketAddrV4, time::Duration};
+use tokio::time::Instant;
+
+use crate::{
+    dht::{decode_peer, encode_peer},
+    tracker::Peer,
+};
+
+/// Name of the extension in the extended handshake
+pub const EXTENSION_NAME: &str = "ut_pex";
+/// Id peers send `ut_pex` messages to us with
+pub const EXTENSION_ID: u8 = 1;
+/// How often peer lists are sent, the minimum BEP 11 allows
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages arriving sooner than this after the previous one from the same peer are ignored
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+/// Most peers added or dropped in a single message
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+/// The peer prefers encrypted connections
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+/// The peer is a seed
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer supports uTP
+pub const FLAG_UTP: u8 = 0x04;
+/// The peer accepted an outgoing connection, so it is reachable
+pub const FLAG_OUTGOING: u8 = 0x10;
+
+/// A `ut_pex` message: compact IPv4 peers joined and left since the previous message
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    /// One flags byte per added peer
+    #[serde(rename = "added.f", default)]
+    pub added_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    /// Added peers that are worth connecting to, with their flags
+    pub fn added_peers(&self) -> Vec<(Peer, u8)> {
+        self.added
+            .chunks(6)
+            .zip((0..).map(|index| self.added_flags.get(index).copied().unwrap_or(0)))
+            .filter_map(|(bytes, flags)| Some((decode_peer(bytes)?, flags)))
+            .filter(|(addr, _)| is_connectable(addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .map(|(addr, flags)| {
+                let peer = Peer {
+                    peer_id: None,
+                    ip: addr.ip().to_string(),
+                    port: addr.port() as i64,
+                };
+                (peer, flags)
+            })
+            .collect()
+    }
+}
+
+/// Rejects addresses no peer can be reached at
+fn is_connectable(addr: &SocketAddrV4) -> bool {
+    let ip = addr.ip();
+    addr.port() != 0
+        && !ip.is_unspecified()
+        && !ip.is_broadcast()
+        && !ip.is_multicast()
+        && !ip.is_documentation()
+}
+
+/// What one connection has exchanged over PEX
+#[derive(Debug, Default)]
+pub struct PexState {
+    /// Peers we told this peer about, with the flags we sent
+    sent: HashMap<SocketAddrV4, u8>,
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    /// The next message for this peer given the peers currently connected, or `None`
+    /// when nothing changed. The first message lists the connected peers themselves.
+    pub fn next_message(&mut self, connected: &HashMap<SocketAddrV4, u8>) -> Option<PexMessage> {
+        let mut message = PexMessage::default();
+        for (addr, flags) in connected {
+            if message.added.len() / 6 == MAX_PEERS_PER_MESSAGE {
+                break;
+            }
+            if !self.sent.contains_key(addr) {
+                message.added.extend(encode_peer(addr));
+                message.added_flags.push(*flags);
+                self.sent.insert(*addr, *flags);
+            }
+        }
+        let dropped = self
+            .sent
+            .keys()
+            .filter(|addr| !connected.contains_key(addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect::<Vec<_>>();
+        for addr in dropped {
+            message.dropped.extend(encode_peer(&addr));
+            self.sent.remove(&addr);
+        }
+        (!message.added.is_empty() || !message.dropped.is_empty()).then_some(message)
+    }
+
+    /// Whether a message received now respects the rate BEP 11 allows
+    pub fn accept_message(&mut self) -> bool {
+        let now = Instant::now();
+        let flooding = self
+            .last_received
+            .is_some_and(|last| now - last < MIN_RECEIVE_INTERVAL);
+        if !flooding {
+            self.last_received = Some(now);
+        }
+        !flooding
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PexMessage, PexState, FLAG_SEED};
+    use serde_bytes::ByteBuf;
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddrV4},
+    };
+
+    #[test]
+    fn it_sends_added_and_dropped_peers() {
+        let first = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let second = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6882);
+        let mut state = PexState::default();
+
+        let message = state
+            .next_message(&HashMap::from([(first, FLAG_SEED)]))
+            .unwrap();
+        let message = PexMessage::from_bytes(&message.to_bytes().unwrap()).unwrap();
+        let added = message.added_peers();
+        assert_eq!(added.len(), 1);
+        assert_eq!(
+            (added[0].0.ip.as_str(), added[0].0.port),
+            ("10.0.0.1", 6881)
+        );
+        assert_eq!(added[0].1, FLAG_SEED);
+        assert!(message.dropped.is_empty());
+
+        assert!(state
+            .next_message(&HashMap::from([(first, FLAG_SEED)]))
+            .is_none());
+        let message = state.next_message(&HashMap::from([(second, 0)])).unwrap();
+        assert_eq!(message.added.as_slice(), [10, 0, 0, 2, 0x1a, 0xe2]);
+        assert_eq!(message.dropped.as_slice(), [10, 0, 0, 1, 0x1a, 0xe1]);
+    }
+
+    #[test]
+    fn it_ignores_floods_and_bogus_peers() {
+        let mut state = PexState::default();
+        assert!(state.accept_message());
+        assert!(!state.accept_message());
+
+        let message = PexMessage {
+            added: ByteBuf::from(
+                [
+                    [0, 0, 0, 0, 0x1a, 0xe1],
+                    [10, 0, 0, 1, 0, 0],
+                    [10, 0, 0, 1, 0x1a, 0xe1],
+                ]
+                .concat(),
+            ),
+            ..Default::default()
+        };
+        let added = message.added_peers();
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].1, 0);
+    }
+}
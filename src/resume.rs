@@ -0,0 +1,125 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{
+    fs::Metadata,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::tracker::TrackerState;
+
+/// Extension of the resume file written next to the downloaded data
+const RESUME_EXTENSION: &str = "fastresume";
+
+/// State of a download persisted between runs, so that startup can skip hashing
+/// pieces that were already verified
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info-hash")]
+    pub info_hash: ByteBuf,
+    /// Bitfield of the verified pieces
+    pub pieces: ByteBuf,
+    /// Pieces with some of their blocks already written to disk
+    pub partial: Vec<PartialPiece>,
+    /// Size and modification time of every file when the resume data was saved
+    pub files: Vec<FileState>,
+    pub tracker: TrackerState,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub piece: u32,
+    /// Bitfield of the blocks received for the piece
+    pub blocks: ByteBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    pub length: u64,
+    /// Nanoseconds since the unix epoch
+    pub mtime: u64,
+}
+
+impl FileState {
+    pub fn from_metadata(metadata: &Metadata) -> Result<Self> {
+        Ok(Self {
+            length: metadata.len(),
+            mtime: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+        })
+    }
+}
+
+impl ResumeData {
+    /// Location of the resume file for the data at `path`
+    pub fn path(path: &Path) -> PathBuf {
+        let mut file_name = path.file_name().unwrap_or_default().to_owned();
+        file_name.push(".");
+        file_name.push(RESUME_EXTENSION);
+        path.with_file_name(file_name)
+    }
+
+    pub async fn load(path: &Path) -> Result<Self> {
+        let bytes = tokio::fs::read(path).await?;
+        Ok(serde_bencode::from_bytes(&bytes)?)
+    }
+
+    /// Writes the resume data through a temporary file, so a crash never leaves a truncated one
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let bytes = serde_bencode::to_bytes(self)?;
+        let temporary = path.with_extension("tmp");
+        tokio::fs::write(&temporary, bytes).await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+
+    /// Whether the resume data describes this torrent and its files were not touched since
+    pub fn matches(&self, info_hash: &[u8; 20], files: &[FileState]) -> bool {
+        self.info_hash.as_slice() == info_hash && self.files == files
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileState, PartialPiece, ResumeData};
+    use serde_bytes::ByteBuf;
+    use std::path::Path;
+
+    #[test]
+    fn it_names_the_resume_file_after_the_data() {
+        assert_eq!(
+            ResumeData::path(Path::new("downloads/debian.iso")),
+            Path::new("downloads/debian.iso.fastresume")
+        );
+    }
+
+    #[tokio::test]
+    async fn it_saves_and_loads_resume_data() {
+        let path = std::env::temp_dir().join(format!("furia-{}.fastresume", std::process::id()));
+        let files = vec![FileState {
+            length: 1024,
+            mtime: 1_700_000_000_000_000_000,
+        }];
+        let resume_data = ResumeData {
+            info_hash: ByteBuf::from(vec![7; 20]),
+            pieces: ByteBuf::from(vec![0b1010_0000]),
+            partial: vec![PartialPiece {
+                piece: 1,
+                blocks: ByteBuf::from(vec![0b1000_0000]),
+            }],
+            files: files.clone(),
+            ..Default::default()
+        };
+        resume_data.save(&path).await.unwrap();
+        let loaded = ResumeData::load(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(loaded, resume_data);
+        assert!(loaded.matches(&[7; 20], &files));
+        let touched = vec![FileState {
+            mtime: 1_700_000_000_000_000_001,
+            ..files[0].clone()
+        }];
+        assert!(!loaded.matches(&[7; 20], &touched));
+    }
+}
@@ -0,0 +1,322 @@
+use anyhow::{anyhow, bail, Result};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use std::{io::SeekFrom, path::Path};
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpListener,
+};
+use tracing::{info, warn, Instrument};
+
+use crate::stream::TorrentHandle;
+
+/// Longest request head accepted, in bytes
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Requested byte range of a file, inclusive
+#[derive(Debug, PartialEq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+/// Parses a single `bytes=` range against a file of `length` bytes. `Ok(None)` is a
+/// header we do not support and answer with the whole file, `Err` is unsatisfiable.
+fn parse_range(header: &str, length: u64) -> Result<Option<ByteRange>> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec
+        .split_once('-')
+        .ok_or_else(|| anyhow!("Malformed range {}", spec))?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>()?.min(length);
+            (length - suffix, length.saturating_sub(1))
+        }
+        (start, "") => (start.parse()?, length.saturating_sub(1)),
+        (start, end) => (
+            start.parse()?,
+            end.parse::<u64>()?.min(length.saturating_sub(1)),
+        ),
+    };
+    if start > end || start >= length {
+        bail!("Range {} not satisfiable for {} bytes", spec, length);
+    }
+    Ok(Some(ByteRange { start, end }))
+}
+
+/// Serves the files of a torrent over HTTP until the listener fails. Files are
+/// addressed by their path in the torrent, or by index as `/<index>`.
+pub async fn serve(listener: TcpListener, handle: TorrentHandle) -> Result<()> {
+    info!("Serving on http://{}", listener.local_addr()?);
+    loop {
+        let (connection, address) = listener.accept().await?;
+        let handle = handle.clone();
+        let span = tracing::span!(tracing::Level::INFO, "http", %address);
+        tokio::spawn(
+            async move {
+                if let Err(e) = handle_connection(connection, &handle).await {
+                    warn!(?e, "HTTP request failed");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn handle_connection<C: AsyncRead + AsyncWrite + Unpin>(
+    connection: C,
+    handle: &TorrentHandle,
+) -> Result<()> {
+    let mut connection = BufReader::new(connection);
+    let mut head = Vec::new();
+    let mut line = String::new();
+    let mut remaining = MAX_REQUEST_HEAD;
+    loop {
+        line.clear();
+        // Reads are bounded so that a line without end cannot grow past the limit
+        let read = (&mut connection)
+            .take(remaining as u64)
+            .read_line(&mut line)
+            .await?;
+        if !line.ends_with('\n') {
+            if read < remaining {
+                return Ok(());
+            }
+            return respond(
+                &mut connection,
+                "431 Request Header Fields Too Large",
+                &[],
+                "",
+            )
+            .await;
+        }
+        remaining -= read;
+        if line == "\r\n" || line == "\n" {
+            break;
+        }
+        head.push(line.trim_end().to_string());
+    }
+    let Some((method, target)) = head.first().and_then(|request| {
+        let mut parts = request.split_whitespace();
+        Some((parts.next()?.to_string(), parts.next()?.to_string()))
+    }) else {
+        return respond(&mut connection, "400 Bad Request", &[], "").await;
+    };
+    let range = head.iter().skip(1).find_map(|header| {
+        let (name, value) = header.split_once(':')?;
+        name.eq_ignore_ascii_case("range")
+            .then(|| value.to_string())
+    });
+    info!(method, target, ?range, "HTTP request");
+    if method != "GET" && method != "HEAD" {
+        return respond(
+            &mut connection,
+            "405 Method Not Allowed",
+            &[("Allow", "GET, HEAD")],
+            "",
+        )
+        .await;
+    }
+
+    let path = percent_decode_str(target.split('?').next().unwrap_or_default()).decode_utf8_lossy();
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        let body = index(handle);
+        return respond(
+            &mut connection,
+            "200 OK",
+            &[("Content-Type", "text/html; charset=utf-8")],
+            &body,
+        )
+        .await;
+    }
+    let file_index = handle
+        .files()
+        .iter()
+        .position(|file| file.path == Path::new(path))
+        .or_else(|| {
+            path.parse()
+                .ok()
+                .filter(|index| *index < handle.files().len())
+        });
+    let Some(file_index) = file_index else {
+        return respond(&mut connection, "404 Not Found", &[], "").await;
+    };
+
+    let mut stream = handle.open_file(file_index)?;
+    let length = stream.len();
+    let (status, range) = match range.map(|range| parse_range(&range, length)) {
+        Some(Ok(Some(range))) => ("206 Partial Content", range),
+        Some(Err(_)) => {
+            let content_range = format!("bytes */{}", length);
+            return respond(
+                &mut connection,
+                "416 Range Not Satisfiable",
+                &[("Content-Range", &content_range)],
+                "",
+            )
+            .await;
+        }
+        _ => (
+            "200 OK",
+            ByteRange {
+                start: 0,
+                end: length.saturating_sub(1),
+            },
+        ),
+    };
+    let content_length = if length == 0 {
+        0
+    } else {
+        range.end - range.start + 1
+    };
+    let content_length_header = content_length.to_string();
+    let content_range = format!("bytes {}-{}/{}", range.start, range.end, length);
+    let mut headers = vec![
+        ("Accept-Ranges", "bytes"),
+        ("Content-Type", "application/octet-stream"),
+        ("Content-Length", content_length_header.as_str()),
+    ];
+    if status.starts_with("206") {
+        headers.push(("Content-Range", &content_range));
+    }
+    write_head(&mut connection, status, &headers).await?;
+    if method == "GET" && content_length > 0 {
+        stream.seek(SeekFrom::Start(range.start)).await?;
+        tokio::io::copy(&mut stream.take(content_length), &mut connection).await?;
+    }
+    connection.shutdown().await?;
+    Ok(())
+}
+
+/// Links to every file of the torrent
+fn index(handle: &TorrentHandle) -> String {
+    let links = handle
+        .files()
+        .iter()
+        .map(|file| {
+            let path = file.path.to_string_lossy();
+            let href = path
+                .split('/')
+                .map(|part| utf8_percent_encode(part, NON_ALPHANUMERIC).to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            let name = path
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+            format!(
+                "<li><a href=\"/{}\">{}</a> ({} bytes)</li>",
+                href, name, file.length
+            )
+        })
+        .collect::<String>();
+    format!(
+        "<!DOCTYPE html><html><body><ul>{}</ul></body></html>",
+        links
+    )
+}
+
+async fn write_head<W: AsyncWrite + Unpin>(
+    connection: &mut W,
+    status: &str,
+    headers: &[(&str, &str)],
+) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    connection.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+async fn respond<W: AsyncWrite + Unpin>(
+    connection: &mut W,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<()> {
+    let content_length = body.len().to_string();
+    let mut all_headers = headers.to_vec();
+    all_headers.push(("Content-Length", &content_length));
+    write_head(connection, status, &all_headers).await?;
+    connection.write_all(body.as_bytes()).await?;
+    connection.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handle_connection, parse_range, serve, ByteRange, MAX_REQUEST_HEAD};
+    use crate::stream::test_handle;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[test]
+    fn it_parses_byte_ranges() {
+        assert_eq!(
+            parse_range("bytes=2-5", 10).unwrap(),
+            Some(ByteRange { start: 2, end: 5 })
+        );
+        assert_eq!(
+            parse_range("bytes=7-", 10).unwrap(),
+            Some(ByteRange { start: 7, end: 9 })
+        );
+        assert_eq!(
+            parse_range("bytes=-3", 10).unwrap(),
+            Some(ByteRange { start: 7, end: 9 })
+        );
+        assert_eq!(parse_range("bytes=0-1,4-5", 10).unwrap(), None);
+        assert!(parse_range("bytes=10-", 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_serves_ranges_of_a_file() {
+        let data = (0..25_u8).collect::<Vec<u8>>();
+        let handle = test_handle(&data, &[("a", 12), ("b", 13)]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, handle));
+
+        let mut connection = TcpStream::connect(address).await.unwrap();
+        connection
+            .write_all(b"GET /test/b HTTP/1.1\r\nHost: localhost\r\nRange: bytes=2-5\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        connection.read_to_end(&mut response).await.unwrap();
+        let (head, body) =
+            response.split_at(response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4);
+        let head = String::from_utf8_lossy(head);
+        assert!(head.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(head.contains("Content-Range: bytes 2-5/13\r\n"));
+        assert_eq!(body, &data[14..18]);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_request_heads_over_the_limit() {
+        let handle = test_handle(&[0; 25], &[("a", 12), ("b", 13)]);
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let connection = tokio::spawn(async move { handle_connection(server, &handle).await });
+
+        // A header line that never ends must not be buffered past the limit
+        client
+            .write_all(&vec![b'a'; MAX_REQUEST_HEAD + 1])
+            .await
+            .unwrap();
+        let mut response = vec![0; 48];
+        client.read_exact(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        connection.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+
+use crate::{
+    disk::DiskSettings,
+    download::{PieceOrder, MAX_PIECES_IN_FLIGHT},
+    hash_check::CheckSettings,
+    storage::Allocation,
+};
+
+/// Tunables for a running torrent
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Skip sending `have` to peers whose bitfield already contains the piece
+    pub suppress_redundant_have: bool,
+    /// Number of partially downloaded pieces buffered in memory
+    pub max_pieces_in_flight: usize,
+    /// Order in which new pieces are started
+    pub piece_order: PieceOrder,
+    /// Start the first and last pieces of every wanted file before the others
+    pub first_and_last_pieces_first: bool,
+    /// Parallelism and throttling of piece verification
+    pub hash_check: CheckSettings,
+    /// Disk threads, write queue bound and read cache size
+    pub disk: DiskSettings,
+    /// How files are sized before their pieces are written
+    pub allocation: Allocation,
+    /// Directory the content ends up in
+    pub save_path: PathBuf,
+    /// Directory the content is downloaded to, moved to `save_path` once every piece is verified
+    pub incomplete_path: Option<PathBuf>,
+    /// Exchange peer lists with connected peers (BEP 11), never done for private torrents
+    pub pex: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            suppress_redundant_have: false,
+            max_pieces_in_flight: MAX_PIECES_IN_FLIGHT,
+            piece_order: PieceOrder::default(),
+            first_and_last_pieces_first: false,
+            hash_check: CheckSettings::default(),
+            disk: DiskSettings::default(),
+            allocation: Allocation::default(),
+            save_path: PathBuf::from("."),
+            incomplete_path: None,
+            pex: true,
+        }
+    }
+}
@@ -0,0 +1,956 @@
+use memmap2::MmapMut;
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use tracing::warn;
+
+use crate::{parse_torrent::TorrentFile, resume::FileState};
+
+/// Where the content of a torrent lives. Offsets are positions in the torrent's
+/// content, as if all of its files were concatenated.
+pub trait Storage: Send + Sync {
+    fn layout(&self) -> &FileLayout;
+
+    /// Fills `buf` with the content starting at `offset`
+    fn read_block(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_piece(&self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    fn flush(&self) -> io::Result<()>;
+
+    fn file_exists(&self, file_index: usize) -> bool;
+
+    /// Whether a file should be created. Backends able to keep the data of pieces
+    /// straddling an unwanted file elsewhere do so until the file is wanted again.
+    fn set_file_wanted(&self, _file_index: usize, _wanted: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Size and modification time of every file, used to validate resume data
+    fn file_states(&self) -> io::Result<Vec<FileState>>;
+
+    /// Checks there is room for the missing data of the wanted files and sizes them
+    /// according to `allocation`
+    fn allocate(&self, _allocation: Allocation) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Relocates every file under `directory`
+    fn move_to(&self, directory: &Path) -> io::Result<()>;
+
+    fn delete(&self) -> io::Result<()>;
+}
+
+/// How files are sized before their pieces are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Allocation {
+    /// Files grow as pieces are written
+    None,
+    /// Files are created at their final size without reserving disk blocks
+    #[default]
+    Sparse,
+    /// Disk blocks are reserved up front, so a full disk is noticed before downloading
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    /// Path relative to the storage root
+    pub path: PathBuf,
+    /// Position of the file's first byte in the torrent's content
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Longest file name most file systems accept, in bytes
+const MAX_NAME_BYTES: usize = 255;
+/// Device names Windows reserves, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a name from a torrent into a single path component that stays inside the
+/// download directory. Separators, NULs, control and reserved characters become `_`,
+/// trailing dots and spaces are dropped, and empty, `.`, `..` or reserved device
+/// names are prefixed with `_`. The same name always gives the same result.
+pub fn sanitize_component(name: &str) -> String {
+    let mut sanitized = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if sanitized.is_empty()
+        || RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        sanitized.insert(0, '_');
+    }
+    if sanitized.len() > MAX_NAME_BYTES {
+        let mut end = MAX_NAME_BYTES;
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+    }
+    if sanitized != name {
+        warn!(name, sanitized, "Renamed unsafe file name from the torrent");
+    }
+    sanitized
+}
+
+/// Appends `_1`, `_2`... to the file name of `path` until it is not in `used`
+fn deduplicate(path: PathBuf, used: &mut HashSet<PathBuf>) -> PathBuf {
+    let mut candidate = path.clone();
+    let mut suffix = 0;
+    while !used.insert(candidate.clone()) {
+        suffix += 1;
+        let mut file_name = path.file_name().unwrap_or_default().to_owned();
+        file_name.push(format!("_{}", suffix));
+        candidate = path.with_file_name(file_name);
+    }
+    candidate
+}
+
+/// Files of a torrent laid out one after the other
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileLayout {
+    pub files: Vec<FileEntry>,
+}
+
+impl FileLayout {
+    pub fn new(files: impl IntoIterator<Item = (PathBuf, u64)>) -> Self {
+        let mut offset = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                let file = FileEntry {
+                    path,
+                    offset,
+                    length,
+                };
+                offset += length;
+                file
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// A single file named after the torrent, or a directory holding its files. Names
+    /// come from an untrusted torrent, so every component goes through
+    /// [`sanitize_component`] and colliding paths get a numbered suffix.
+    pub fn from_torrent(torrent: &TorrentFile) -> Self {
+        let name = PathBuf::from(sanitize_component(&torrent.info.name));
+        match &torrent.info.files {
+            Some(files) => {
+                let mut used = HashSet::new();
+                Self::new(files.iter().map(|file| {
+                    let mut path = name.clone();
+                    if file.path.is_empty() {
+                        path.push(sanitize_component(""));
+                    }
+                    for part in &file.path {
+                        path.push(sanitize_component(part));
+                    }
+                    let path = deduplicate(path, &mut used);
+                    (path, file.length as u64)
+                }))
+            }
+            None => Self::new([(name, torrent.info.total_length())]),
+        }
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files
+            .last()
+            .map_or(0, |file| file.offset + file.length)
+    }
+
+    /// Splits `length` bytes of content starting at `offset` by file, as
+    /// (file index, offset in the file, range of the buffer)
+    pub fn spans(&self, offset: u64, length: usize) -> Vec<(usize, u64, Range<usize>)> {
+        let end = offset + length as u64;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| {
+                file.length > 0 && file.offset < end && offset < file.offset + file.length
+            })
+            .map(|(file_index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                let buffer_start = (start - offset) as usize;
+                (
+                    file_index,
+                    start - file.offset,
+                    buffer_start..buffer_start + (stop - start) as usize,
+                )
+            })
+            .collect()
+    }
+}
+
+fn out_of_range() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Range past the end of the torrent",
+    )
+}
+
+fn file_state(path: &Path) -> io::Result<FileState> {
+    match fs::metadata(path) {
+        Ok(metadata) => FileState::from_metadata(&metadata).map_err(io::Error::other),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(FileState {
+            length: 0,
+            mtime: 0,
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves a file or a directory. Data that cannot be renamed is copied under a temporary
+/// name first, so the destination never shows a partial file. A directory moved onto an
+/// existing one is merged into it entry by entry, so that one fills up gradually.
+fn move_entry(source: &Path, destination: &Path) -> io::Result<()> {
+    if !source.exists() {
+        return Ok(());
+    }
+    if source.is_dir() && destination.is_dir() {
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            move_entry(&entry.path(), &destination.join(entry.file_name()))?;
+        }
+        return fs::remove_dir(source);
+    }
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(source, destination).is_ok() {
+        return Ok(());
+    }
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(destination.file_name().unwrap_or_default());
+    temporary_name.push(".moving");
+    let temporary = destination.with_file_name(temporary_name);
+    copy_entry(source, &temporary)?;
+    fs::rename(&temporary, destination)?;
+    if source.is_dir() {
+        fs::remove_dir_all(source)
+    } else {
+        fs::remove_file(source)
+    }
+}
+
+fn copy_entry(source: &Path, destination: &Path) -> io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, destination).map(|_| ());
+    }
+    fs::create_dir_all(destination)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        copy_entry(&entry.path(), &destination.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// Moves the files of `layout` from `from` to `to`, one top-level entry at a time so
+/// the directory of a multi-file torrent appears at once
+fn move_files(layout: &FileLayout, from: &Path, to: &Path) -> io::Result<()> {
+    let mut entries = layout
+        .files
+        .iter()
+        .filter_map(|file| file.path.components().next())
+        .collect::<Vec<_>>();
+    entries.dedup();
+    for entry in entries {
+        move_entry(&from.join(entry), &to.join(entry))?;
+    }
+    Ok(())
+}
+
+fn delete_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn delete_files(layout: &FileLayout, root: &Path) -> io::Result<()> {
+    for file in &layout.files {
+        delete_file(&root.join(&file.path))?;
+    }
+    Ok(())
+}
+
+/// Returns the cached handle of `path`, reopening it for writing (and creating it) if needed
+fn open_cached(
+    handle: &Mutex<Option<OpenFile>>,
+    path: &Path,
+    write: bool,
+) -> io::Result<Arc<File>> {
+    let mut handle = handle.lock().unwrap();
+    if let Some((file, writable)) = handle.as_ref() {
+        if *writable || !write {
+            return Ok(file.clone());
+        }
+    }
+    if write {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let file = Arc::new(
+        OpenOptions::new()
+            .read(true)
+            .write(write)
+            .create(write)
+            .truncate(false)
+            .open(path)?,
+    );
+    *handle = Some((file.clone(), write));
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        let written = file.seek_write(data, offset)?;
+        data = &data[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+/// Copies `length` bytes between files, leaving holes where the source only has zeros
+fn copy_sparse(
+    from: &File,
+    from_offset: u64,
+    to: &File,
+    to_offset: u64,
+    length: u64,
+) -> io::Result<()> {
+    const CHUNK: u64 = 64 * 1024;
+    let length = length.min(from.metadata()?.len().saturating_sub(from_offset));
+    let mut buf = vec![0; CHUNK as usize];
+    let mut copied = 0;
+    while copied < length {
+        let chunk = &mut buf[..CHUNK.min(length - copied) as usize];
+        read_at(from, chunk, from_offset + copied)?;
+        if chunk.iter().any(|byte| *byte != 0) {
+            write_at(to, chunk, to_offset + copied)?;
+        }
+        copied += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // statvfs field types differ between platforms
+fn available_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: `path` is NUL terminated and `stats` is only read after statvfs filled it
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stats = unsafe { stats.assume_init() };
+    Ok(Some(stats.f_bavail as u64 * stats.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Reserves the disk blocks of the first `length` bytes of a file
+#[cfg(target_os = "linux")]
+fn allocate_full(file: &File, length: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: the descriptor stays open for the duration of the call
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate_full(file: &File, length: u64) -> io::Result<()> {
+    file.set_len(length)
+}
+
+/// Open file handle and whether it was opened for writing
+type OpenFile = (Arc<File>, bool);
+
+/// Files on the local filesystem, opened on first use and accessed with positional
+/// reads and writes so that several threads can use them at once.
+///
+/// Data of unwanted files that were never created goes to a sparse part file at its
+/// position in the torrent's content, so that pieces straddling them can still be
+/// verified and served.
+pub struct FsStorage {
+    layout: FileLayout,
+    root: RwLock<PathBuf>,
+    handles: Vec<Mutex<Option<OpenFile>>>,
+    wanted: Vec<AtomicBool>,
+    part: Mutex<Option<OpenFile>>,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>, layout: FileLayout) -> Self {
+        Self {
+            handles: layout.files.iter().map(|_| Mutex::new(None)).collect(),
+            wanted: layout.files.iter().map(|_| AtomicBool::new(true)).collect(),
+            part: Mutex::new(None),
+            layout,
+            root: RwLock::new(root.into()),
+        }
+    }
+
+    /// Location of the part file, named after the torrent's top level file or directory
+    pub fn part_path(&self) -> PathBuf {
+        let name = self
+            .layout
+            .files
+            .first()
+            .and_then(|file| file.path.components().next())
+            .map_or("furia".into(), |name| name.as_os_str().to_owned());
+        let mut file_name = name;
+        file_name.push(".parts");
+        self.root().join(file_name)
+    }
+
+    /// Whether the data of a file lives in the part file rather than the file itself
+    fn in_part_file(&self, file_index: usize) -> bool {
+        !self.wanted[file_index].load(Ordering::Relaxed) && !self.file_exists(file_index)
+    }
+
+    /// Opens the handle to use for a span of a file, as (file, offset of the span in it)
+    fn open_span(
+        &self,
+        file_index: usize,
+        file_offset: u64,
+        write: bool,
+    ) -> io::Result<(Arc<File>, u64)> {
+        if self.in_part_file(file_index) {
+            let offset = self.layout.files[file_index].offset + file_offset;
+            Ok((open_cached(&self.part, &self.part_path(), write)?, offset))
+        } else {
+            Ok((self.open(file_index, write)?, file_offset))
+        }
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.root.read().unwrap().clone()
+    }
+
+    fn path(&self, file_index: usize) -> PathBuf {
+        self.root().join(&self.layout.files[file_index].path)
+    }
+
+    fn open(&self, file_index: usize, write: bool) -> io::Result<Arc<File>> {
+        open_cached(&self.handles[file_index], &self.path(file_index), write)
+    }
+
+    fn close_all(&self) {
+        for handle in self.handles.iter().chain([&self.part]) {
+            handle.lock().unwrap().take();
+        }
+    }
+}
+
+impl Storage for FsStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn read_block(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.layout.total_length() {
+            return Err(out_of_range());
+        }
+        for (file_index, file_offset, range) in self.layout.spans(offset, buf.len()) {
+            let (file, file_offset) = self.open_span(file_index, file_offset, false)?;
+            read_at(&file, &mut buf[range], file_offset)?;
+        }
+        Ok(())
+    }
+
+    fn write_piece(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if offset + data.len() as u64 > self.layout.total_length() {
+            return Err(out_of_range());
+        }
+        for (file_index, file_offset, range) in self.layout.spans(offset, data.len()) {
+            let (file, file_offset) = self.open_span(file_index, file_offset, true)?;
+            write_at(&file, &data[range], file_offset)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for handle in self.handles.iter().chain([&self.part]) {
+            if let Some((file, true)) = handle.lock().unwrap().as_ref() {
+                file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn file_exists(&self, file_index: usize) -> bool {
+        self.path(file_index).exists()
+    }
+
+    /// Moves the data kept in the part file into a file that becomes wanted
+    fn set_file_wanted(&self, file_index: usize, wanted: bool) -> io::Result<()> {
+        if wanted && self.in_part_file(file_index) && self.part_path().exists() {
+            let file = &self.layout.files[file_index];
+            let part = open_cached(&self.part, &self.part_path(), false)?;
+            let destination = self.open(file_index, true)?;
+            copy_sparse(&part, file.offset, &destination, 0, file.length)?;
+        }
+        self.wanted[file_index].store(wanted, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        (0..self.layout.files.len())
+            .map(|file_index| file_state(&self.path(file_index)))
+            .collect()
+    }
+
+    fn allocate(&self, allocation: Allocation) -> io::Result<()> {
+        let root = self.root();
+        fs::create_dir_all(&root)?;
+        let wanted = (0..self.layout.files.len())
+            .filter(|&file_index| self.layout.files[file_index].length > 0)
+            .filter(|&file_index| !self.in_part_file(file_index))
+            .collect::<Vec<_>>();
+        let mut needed = 0;
+        for &file_index in &wanted {
+            let existing = file_state(&self.path(file_index))?.length;
+            needed += self.layout.files[file_index]
+                .length
+                .saturating_sub(existing);
+        }
+        if let Some(available) = available_space(&root)? {
+            if needed > available {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!(
+                        "{} needs {} more bytes but only {} are free",
+                        root.display(),
+                        needed,
+                        available
+                    ),
+                ));
+            }
+        }
+
+        for file_index in wanted {
+            let length = self.layout.files[file_index].length;
+            match allocation {
+                Allocation::None => {}
+                Allocation::Sparse => {
+                    let file = self.open(file_index, true)?;
+                    if file.metadata()?.len() < length {
+                        file.set_len(length)?;
+                    }
+                }
+                Allocation::Full => allocate_full(&*self.open(file_index, true)?, length)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn move_to(&self, directory: &Path) -> io::Result<()> {
+        self.flush()?;
+        let part_path = self.part_path();
+        let mut root = self.root.write().unwrap();
+        if *root == directory {
+            return Ok(());
+        }
+        self.close_all();
+        move_files(&self.layout, &root, directory)?;
+        if let Some(part_name) = part_path.file_name() {
+            move_entry(&part_path, &directory.join(part_name))?;
+        }
+        *root = directory.to_owned();
+        Ok(())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        self.close_all();
+        delete_files(&self.layout, &self.root())?;
+        delete_file(&self.part_path())
+    }
+}
+
+/// Content kept in a single in-memory buffer
+pub struct MemoryStorage {
+    layout: FileLayout,
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(layout: FileLayout) -> Self {
+        Self {
+            data: RwLock::new(vec![0; layout.total_length() as usize]),
+            layout,
+        }
+    }
+
+    /// Storage already holding `data`, e.g. to seed from memory
+    pub fn with_data(layout: FileLayout, data: Vec<u8>) -> Self {
+        let storage = Self::new(layout);
+        let size = data.len().min(storage.layout.total_length() as usize);
+        storage.data.write().unwrap()[..size].copy_from_slice(&data[..size]);
+        storage
+    }
+
+    /// Copy of the whole content
+    pub fn data(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn read_block(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.data.read().unwrap();
+        let source = data
+            .get(offset as usize..offset as usize + buf.len())
+            .ok_or_else(out_of_range)?;
+        buf.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_piece(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut content = self.data.write().unwrap();
+        content
+            .get_mut(offset as usize..offset as usize + data.len())
+            .ok_or_else(out_of_range)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn file_exists(&self, _file_index: usize) -> bool {
+        true
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        Ok(self
+            .layout
+            .files
+            .iter()
+            .map(|file| FileState {
+                length: file.length,
+                mtime: 0,
+            })
+            .collect())
+    }
+
+    fn move_to(&self, _directory: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        self.data.write().unwrap().fill(0);
+        Ok(())
+    }
+}
+
+/// Files on the local filesystem, sized up front and memory mapped
+pub struct MmapStorage {
+    layout: FileLayout,
+    root: RwLock<PathBuf>,
+    maps: Vec<Mutex<Option<MmapMut>>>,
+}
+
+impl MmapStorage {
+    pub fn new(root: impl Into<PathBuf>, layout: FileLayout) -> io::Result<Self> {
+        let storage = Self {
+            maps: layout.files.iter().map(|_| Mutex::new(None)).collect(),
+            layout,
+            root: RwLock::new(root.into()),
+        };
+        storage.map_all()?;
+        Ok(storage)
+    }
+
+    fn map_all(&self) -> io::Result<()> {
+        let root = self.root.read().unwrap();
+        for (file, map) in self.layout.files.iter().zip(&self.maps) {
+            if file.length == 0 {
+                continue;
+            }
+            let path = root.join(&file.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if handle.metadata()?.len() < file.length {
+                handle.set_len(file.length)?;
+            }
+            // Safety: the file is owned by this storage for as long as it is mapped
+            *map.lock().unwrap() = Some(unsafe { MmapMut::map_mut(&handle)? });
+        }
+        Ok(())
+    }
+
+    fn unmap_all(&self) -> io::Result<()> {
+        for map in &self.maps {
+            if let Some(map) = map.lock().unwrap().take() {
+                map.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn read_block(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.layout.total_length() {
+            return Err(out_of_range());
+        }
+        for (file_index, file_offset, range) in self.layout.spans(offset, buf.len()) {
+            let map = self.maps[file_index].lock().unwrap();
+            let map = map.as_ref().ok_or_else(out_of_range)?;
+            let start = file_offset as usize;
+            buf[range.clone()].copy_from_slice(&map[start..start + range.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_piece(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if offset + data.len() as u64 > self.layout.total_length() {
+            return Err(out_of_range());
+        }
+        for (file_index, file_offset, range) in self.layout.spans(offset, data.len()) {
+            let mut map = self.maps[file_index].lock().unwrap();
+            let map = map.as_mut().ok_or_else(out_of_range)?;
+            let start = file_offset as usize;
+            map[start..start + range.len()].copy_from_slice(&data[range]);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for map in &self.maps {
+            if let Some(map) = map.lock().unwrap().as_ref() {
+                map.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn file_exists(&self, file_index: usize) -> bool {
+        let root = self.root.read().unwrap();
+        root.join(&self.layout.files[file_index].path).exists()
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        let root = self.root.read().unwrap();
+        self.layout
+            .files
+            .iter()
+            .map(|file| file_state(&root.join(&file.path)))
+            .collect()
+    }
+
+    fn move_to(&self, directory: &Path) -> io::Result<()> {
+        self.unmap_all()?;
+        {
+            let mut root = self.root.write().unwrap();
+            if *root != directory {
+                move_files(&self.layout, &root, directory)?;
+            }
+            *root = directory.to_owned();
+        }
+        self.map_all()
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        self.unmap_all()?;
+        delete_files(&self.layout, &self.root.read().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        sanitize_component, Allocation, FileLayout, FsStorage, MemoryStorage, MmapStorage, Storage,
+    };
+    use crate::parse_torrent::{single_file_torrent, File};
+    use std::path::PathBuf;
+
+    fn layout() -> FileLayout {
+        FileLayout::new([
+            (PathBuf::from("dir/a"), 5),
+            (PathBuf::from("dir/empty"), 0),
+            (PathBuf::from("dir/b"), 3),
+            (PathBuf::from("dir/c"), 4),
+        ])
+    }
+
+    fn round_trip(storage: &dyn Storage) {
+        storage.write_piece(0, b"hello").unwrap();
+        storage.write_piece(5, b"foobar!").unwrap();
+        assert!(storage.write_piece(10, b"xyz").is_err());
+
+        let mut buf = vec![0; 6];
+        storage.read_block(3, &mut buf).unwrap();
+        assert_eq!(&buf, b"lofoob");
+        storage.flush().unwrap();
+    }
+
+    #[test]
+    fn it_splits_ranges_across_files() {
+        assert_eq!(
+            layout().spans(3, 6),
+            vec![(0, 3, 0..2), (2, 0, 2..5), (3, 0, 5..6)]
+        );
+        assert_eq!(layout().total_length(), 12);
+    }
+
+    #[test]
+    fn it_sanitizes_names_from_the_torrent() {
+        assert_eq!(sanitize_component(".."), "_");
+        assert_eq!(sanitize_component("/etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_component("a\\b\0c"), "a_b_c");
+        assert_eq!(sanitize_component("con.txt"), "_con.txt");
+        assert_eq!(sanitize_component("movie. "), "movie");
+        assert_eq!(sanitize_component(&"é".repeat(200)).len(), 254);
+
+        let mut torrent = single_file_torrent(b"0123456789", 10);
+        torrent.info.name = "../evil".to_string();
+        torrent.info.length = None;
+        torrent.info.files = Some(
+            [vec!["..", "x"], vec!["_", "x"], vec!["/abs"]]
+                .into_iter()
+                .map(|path| File {
+                    path: path.into_iter().map(String::from).collect(),
+                    length: 2,
+                    md5sum: None,
+                })
+                .collect(),
+        );
+        let paths = FileLayout::from_torrent(&torrent)
+            .files
+            .into_iter()
+            .map(|file| file.path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from(".._evil/_/x"),
+                PathBuf::from(".._evil/_/x_1"),
+                PathBuf::from(".._evil/_abs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_refuses_to_allocate_more_than_the_free_space() {
+        let root = std::env::temp_dir().join(format!("furia-full-{}", std::process::id()));
+        let layout = FileLayout::new([(PathBuf::from("huge"), u64::MAX / 2)]);
+        let storage = FsStorage::new(&root, layout);
+        let error = storage.allocate(Allocation::None).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+        assert!(!root.join("huge").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn it_reads_and_writes_every_backend() {
+        round_trip(&MemoryStorage::new(layout()));
+
+        let root = std::env::temp_dir().join(format!("furia-storage-{}", std::process::id()));
+        let storage = FsStorage::new(&root, layout());
+        storage.allocate(Allocation::Sparse).unwrap();
+        assert_eq!(std::fs::metadata(root.join("dir/c")).unwrap().len(), 4);
+        round_trip(&storage);
+        assert!(storage.file_exists(0));
+        let moved = root.join("moved");
+        storage.move_to(&moved).unwrap();
+        assert!(moved.join("dir/b").exists() && !root.join("dir/b").exists());
+        let mut buf = vec![0; 3];
+        storage.read_block(5, &mut buf).unwrap();
+        assert_eq!(&buf, b"foo");
+        storage.delete().unwrap();
+        assert!(!storage.file_exists(0));
+
+        let storage = FsStorage::new(root.join("parts"), layout());
+        storage.set_file_wanted(2, false).unwrap();
+        round_trip(&storage);
+        assert!(!root.join("parts/dir/b").exists() && storage.part_path().exists());
+        storage.set_file_wanted(2, true).unwrap();
+        assert_eq!(std::fs::read(root.join("parts/dir/b")).unwrap(), b"foo");
+        storage.delete().unwrap();
+        assert!(!storage.part_path().exists());
+
+        let storage = MmapStorage::new(root.join("mmap"), layout()).unwrap();
+        round_trip(&storage);
+        storage.delete().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{anyhow, Result};
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    sync::{mpsc, oneshot, watch, Mutex, Notify},
+};
+
+use crate::{
+    disk::DiskIo, download::Download, hash_check::CheckProgress, peers::Command, storage::FileEntry,
+};
+
+/// How soon the piece a reader is blocked on should be downloaded
+const READ_DEADLINE: Duration = Duration::from_secs(2);
+/// Pieces after the one being read that are asked for with later deadlines
+const READ_AHEAD_PIECES: usize = 4;
+
+/// Shared view of a running torrent, to read its content while it downloads
+#[derive(Clone)]
+pub struct TorrentHandle {
+    download: Arc<Mutex<Download>>,
+    disk: Arc<DiskIo>,
+    /// Bumped by the coordinator every time a piece is verified
+    verified: watch::Receiver<u64>,
+    /// Tells the coordinator that deadlines changed and peers should be asked for pieces
+    wake: Arc<Notify>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl TorrentHandle {
+    pub(crate) fn new(
+        download: Arc<Mutex<Download>>,
+        disk: Arc<DiskIo>,
+        verified: watch::Receiver<u64>,
+        wake: Arc<Notify>,
+        commands: mpsc::UnboundedSender<Command>,
+    ) -> Self {
+        Self {
+            download,
+            disk,
+            verified,
+            wake,
+            commands,
+        }
+    }
+
+    /// Files of the torrent, in the order of the metainfo
+    pub fn files(&self) -> &[FileEntry] {
+        &self.disk.storage().layout().files
+    }
+
+    /// Opens a file of the torrent for reading; reads wait for the pieces they need
+    pub fn open_file(&self, file_index: usize) -> io::Result<FileStream> {
+        let file = self
+            .disk
+            .storage()
+            .layout()
+            .files
+            .get(file_index)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "No such file in the torrent")
+            })?;
+        Ok(FileStream {
+            handle: self.clone(),
+            offset: file.offset,
+            length: file.length,
+            position: 0,
+            chunk: Vec::new(),
+            pending: None,
+        })
+    }
+
+    /// Relocates the torrent's files under `directory` while it keeps downloading
+    pub async fn move_storage(&self, directory: &Path) -> io::Result<()> {
+        self.disk.move_storage(directory).await
+    }
+
+    /// Hashes the data on disk again, e.g. after it was modified outside of furia. No
+    /// piece is requested until the check is over.
+    pub async fn recheck(&self, progress: watch::Sender<CheckProgress>) -> Result<()> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.commands
+            .send(Command::Recheck { progress, reply })
+            .map_err(|_| anyhow!("Torrent stopped"))?;
+        reply_rx.await.map_err(|_| anyhow!("Torrent stopped"))?
+    }
+
+    /// Asks the coordinator to disconnect its peers, save its state and return
+    pub fn stop(&self) {
+        let _ = self.commands.send(Command::Stop);
+    }
+
+    /// Reads from `offset` up to the end of its piece or `limit` bytes, once the piece is verified
+    async fn read(self, offset: u64, limit: u64, readahead_end: u64) -> io::Result<Vec<u8>> {
+        let (piece_index, length, pieces) = {
+            let download = self.download.lock().await;
+            let piece_index = download.piece_at(offset);
+            let piece_end =
+                download.piece_offset(piece_index) + download.piece_size(piece_index) as u64;
+            let last_piece = download
+                .piece_at(readahead_end.saturating_sub(1))
+                .max(piece_index);
+            (
+                piece_index,
+                limit.min(piece_end.saturating_sub(offset)) as usize,
+                piece_index..last_piece + 1,
+            )
+        };
+        self.wait_for_piece(piece_index, pieces).await?;
+        self.disk.read_block(offset, length).await
+    }
+
+    /// Sets deadlines on `pieces` until the first one is verified
+    async fn wait_for_piece(&self, piece_index: usize, pieces: Range<usize>) -> io::Result<()> {
+        let mut verified = self.verified.clone();
+        loop {
+            verified.borrow_and_update();
+            {
+                let mut download = self.download.lock().await;
+                if download.has_piece(piece_index) {
+                    return Ok(());
+                }
+                let now = Instant::now();
+                for (ahead, piece) in pieces.clone().take(READ_AHEAD_PIECES + 1).enumerate() {
+                    download.set_deadline(piece, now + READ_DEADLINE * (ahead as u32 + 1));
+                }
+            }
+            self.wake.notify_one();
+            verified
+                .changed()
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Torrent stopped"))?;
+        }
+    }
+}
+
+type ReadFuture = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// A file of a torrent, readable and seekable while the torrent downloads
+pub struct FileStream {
+    handle: TorrentHandle,
+    /// Position of the file in the torrent's content
+    offset: u64,
+    length: u64,
+    position: u64,
+    /// Data at `position` read but not returned yet
+    chunk: Vec<u8>,
+    pending: Option<ReadFuture>,
+}
+
+impl FileStream {
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.chunk.is_empty() {
+            if this.position >= this.length {
+                return Poll::Ready(Ok(()));
+            }
+            let pending = this.pending.get_or_insert_with(|| {
+                Box::pin(this.handle.clone().read(
+                    this.offset + this.position,
+                    this.length - this.position,
+                    this.offset + this.length,
+                ))
+            });
+            let chunk = ready!(pending.as_mut().poll(cx));
+            this.pending = None;
+            this.chunk = chunk?;
+        }
+        let length = this.chunk.len().min(buf.remaining());
+        buf.put_slice(&this.chunk[..length]);
+        this.chunk.drain(..length);
+        this.position += length as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start of the file",
+            )
+        })?;
+        self.position = position;
+        self.chunk.clear();
+        self.pending = None;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+/// Handle over fully verified in-memory content made of `files`, as (name, length)
+#[cfg(test)]
+pub(crate) fn test_handle(data: &[u8], files: &[(&str, i64)]) -> TorrentHandle {
+    use crate::{
+        disk::DiskSettings,
+        parse_torrent::multi_file_torrent,
+        storage::{FileLayout, MemoryStorage},
+    };
+
+    let torrent = multi_file_torrent(data, 10, files);
+    let mut download = Download::from(&torrent);
+    for piece_index in 0..download.piece_count() {
+        download.set_verified(piece_index);
+    }
+    let layout = FileLayout::from_torrent(&torrent);
+    let storage = Arc::new(MemoryStorage::with_data(layout, data.to_vec()));
+    let (disk, _) = DiskIo::new(storage, 10, &DiskSettings::default());
+    TorrentHandle::new(
+        Arc::new(Mutex::new(download)),
+        Arc::new(disk),
+        watch::channel(0).1,
+        Arc::new(Notify::new()),
+        mpsc::unbounded_channel().0,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::TorrentHandle;
+    use crate::{
+        disk::{DiskIo, DiskSettings},
+        download::Download,
+        parse_torrent::multi_file_torrent,
+        storage::{FileLayout, MemoryStorage, Storage},
+    };
+    use std::{io::SeekFrom, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncSeekExt},
+        sync::{mpsc, watch, Mutex, Notify},
+    };
+
+    #[tokio::test]
+    async fn it_reads_a_file_as_its_pieces_are_verified() {
+        let data = (0..25_u8).collect::<Vec<u8>>();
+        let torrent = multi_file_torrent(&data, 10, &[("a", 12), ("b", 13)]);
+        let mut download = Download::from(&torrent);
+        download.set_verified(0);
+        download.set_verified(1);
+        let download = Arc::new(Mutex::new(download));
+        let layout = FileLayout::from_torrent(&torrent);
+        let storage = Arc::new(MemoryStorage::with_data(layout, data[..20].to_vec()));
+        let (disk, _) = DiskIo::new(storage.clone(), 10, &DiskSettings::default());
+        let (verified_tx, verified_rx) = watch::channel(0);
+        let wake = Arc::new(Notify::new());
+        let handle = TorrentHandle::new(
+            download.clone(),
+            Arc::new(disk),
+            verified_rx,
+            wake.clone(),
+            mpsc::unbounded_channel().0,
+        );
+
+        let mut stream = handle.open_file(1).unwrap();
+        assert_eq!(stream.len(), 13);
+        stream.seek(SeekFrom::Start(3)).await.unwrap();
+        let mut start = vec![0; 3];
+        stream.read_exact(&mut start).await.unwrap();
+        assert_eq!(start, &data[15..18]);
+
+        let reader = tokio::spawn(async move {
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+        wake.notified().await;
+        storage.write_piece(20, &data[20..]).unwrap();
+        download.lock().await.set_verified(2);
+        verified_tx.send_modify(|verified| *verified += 1);
+        assert_eq!(reader.await.unwrap(), &data[18..]);
+    }
+}
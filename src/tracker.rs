@@ -1,14 +1,16 @@
 use anyhow::Result;
 use percent_encoding::percent_encode_byte;
-use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use url::Url;
 
 use crate::parse_torrent::{Info, TorrentFile};
 
+/// Port announced to trackers and the DHT
+pub const LISTEN_PORT: u16 = 6881;
+
 #[derive(Debug, Serialize, Deserialize)]
-enum Event {
+pub enum Event {
     Started,
     Stopped,
     Completed,
@@ -18,11 +20,36 @@ enum Event {
 struct TrackerRequest {
     peer_id: String,
     port: isize,
-    uploaded: usize,
-    downloaded: usize,
-    left: usize,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
     compact: bool,
     no_peer_id: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trackerid: Option<String>,
+}
+
+/// What we reported to and learned from the tracker, kept across restarts
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrackerState {
+    #[serde(
+        default,
+        rename = "tracker id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tracker_id: Option<String>,
+    pub interval: u32,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl TrackerState {
+    pub fn update(&mut self, response: &TrackerResponse) {
+        self.interval = response.interval;
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id.clone();
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -36,15 +63,15 @@ pub struct Peer {
 #[derive(Debug, Deserialize)]
 pub struct TrackerResponse {
     #[serde(rename = "failure reason")]
-    failure_reason: Option<bool>,
+    pub failure_reason: Option<String>,
     #[serde(rename = "warning message")]
-    warning_message: Option<bool>,
+    pub warning_message: Option<String>,
     /// Interval in seconds that the client should wait between sending regular requests to the tracker
-    interval: u32,
+    pub interval: u32,
     #[serde(rename = "tracker id")]
-    tracker_id: Option<String>,
-    complete: Option<u32>,
-    incomplete: Option<u32>,
+    pub tracker_id: Option<String>,
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
     #[serde(with = "peer_list")]
     pub peers: Vec<Peer>,
 }
@@ -52,7 +79,7 @@ pub struct TrackerResponse {
 mod peer_list {
     use super::Peer;
     use serde::{Deserialize, Deserializer};
-    use serde_bytes::{ByteArray, ByteBuf};
+    use serde_bytes::ByteBuf;
     use tracing::info;
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Peer>, D::Error>
@@ -87,7 +114,7 @@ pub fn get_info_hash(info: &Info) -> Result<[u8; 20]> {
 }
 
 pub fn get_encoded_info_hash(info: &Info) -> Result<String> {
-    let info_hash = get_info_hash(&info)?; // Vec<u8>
+    let info_hash = get_info_hash(info)?;
     let info_hash = info_hash
         .into_iter()
         .map(percent_encode_byte)
@@ -95,17 +122,23 @@ pub fn get_encoded_info_hash(info: &Info) -> Result<String> {
     Ok(info_hash)
 }
 
-pub async fn request_tracker(torrent: &TorrentFile, peer_id: &str) -> Result<TrackerResponse> {
+pub async fn request_tracker(
+    torrent: &TorrentFile,
+    peer_id: &str,
+    state: &TrackerState,
+    left: u64,
+) -> Result<TrackerResponse> {
     let info_hash = get_encoded_info_hash(&torrent.info)?;
 
     let tracker_request = TrackerRequest {
         peer_id: peer_id.to_owned(),
-        port: 6881,
-        uploaded: 0,
-        downloaded: 0,
-        left: 0,
+        port: LISTEN_PORT as isize,
+        uploaded: state.uploaded,
+        downloaded: state.downloaded,
+        left,
         compact: true,
         no_peer_id: true,
+        trackerid: state.tracker_id.clone(),
     };
     let url = Url::parse(&torrent.announce)?;
     let url = url.join(&format!("?info_hash={}", &info_hash)).unwrap();
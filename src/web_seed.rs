@@ -0,0 +1,188 @@
+use anyhow::{anyhow, bail, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header::RANGE, Client, StatusCode};
+use std::{ops::Range, time::Duration};
+
+use crate::{parse_torrent::TorrentFile, storage::FileLayout};
+
+/// Characters left as they are in the path segments of a web seed URL
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+/// Longest time a web seed request may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How a web seed serves the content of a torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// BEP 19 `url-list`: the files themselves, fetched with HTTP range requests
+    UrlList,
+    /// BEP 17 `httpseeds`: a script answering with ranges of a piece
+    HttpSeed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind,
+}
+
+impl WebSeed {
+    /// Every web seed of a torrent, `url-list` ones first
+    pub fn from_torrent(torrent: &TorrentFile) -> Vec<Self> {
+        let url_list = torrent.web_seeds().into_iter().map(|url| Self {
+            url,
+            kind: WebSeedKind::UrlList,
+        });
+        let http_seeds = torrent.httpseeds.iter().flatten().map(|url| Self {
+            url: url.clone(),
+            kind: WebSeedKind::HttpSeed,
+        });
+        url_list.chain(http_seeds).collect()
+    }
+}
+
+/// Fetches the torrent's content from web seeds
+pub struct WebSeedClient {
+    client: Client,
+    layout: FileLayout,
+    /// Path of every file in the torrent, relative to a `url-list` URL
+    file_paths: Vec<String>,
+    single_file: bool,
+    piece_length: u64,
+    encoded_info_hash: String,
+}
+
+impl WebSeedClient {
+    pub fn new(torrent: &TorrentFile, encoded_info_hash: String) -> Result<Self> {
+        let name = utf8_percent_encode(&torrent.info.name, PATH_SEGMENT).to_string();
+        let file_paths = match &torrent.info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    file.path.iter().fold(name.clone(), |path, part| {
+                        format!("{}/{}", path, utf8_percent_encode(part, PATH_SEGMENT))
+                    })
+                })
+                .collect(),
+            None => vec![name],
+        };
+        Ok(Self {
+            client: Client::builder().timeout(REQUEST_TIMEOUT).build()?,
+            layout: FileLayout::from_torrent(torrent),
+            file_paths,
+            single_file: torrent.info.files.is_none(),
+            piece_length: torrent.info.piece_length as u64,
+            encoded_info_hash,
+        })
+    }
+
+    /// Fetches `range` of a piece, which may span several files
+    pub async fn fetch(
+        &self,
+        seed: &WebSeed,
+        piece_index: usize,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>> {
+        let data = match seed.kind {
+            WebSeedKind::UrlList => {
+                let offset = piece_index as u64 * self.piece_length + range.start;
+                let length = (range.end - range.start) as usize;
+                let mut data = Vec::with_capacity(length);
+                for (file_index, file_offset, span) in self.layout.spans(offset, length) {
+                    let url = self.file_url(&seed.url, file_index);
+                    let end = file_offset + span.len() as u64;
+                    data.extend(self.fetch_range(&url, file_offset..end).await?);
+                }
+                data
+            }
+            WebSeedKind::HttpSeed => {
+                let url = format!(
+                    "{}{}info_hash={}&piece={}&ranges={}-{}",
+                    seed.url,
+                    if seed.url.contains('?') { '&' } else { '?' },
+                    self.encoded_info_hash,
+                    piece_index,
+                    range.start,
+                    range.end - 1
+                );
+                let response = self.client.get(&url).send().await?;
+                if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+                    let retry = response.text().await.unwrap_or_default();
+                    bail!("Seed busy, retry in {} seconds", retry.trim());
+                }
+                response.error_for_status()?.bytes().await?.to_vec()
+            }
+        };
+        if data.len() as u64 != range.end - range.start {
+            bail!(
+                "Web seed sent {} bytes instead of {}",
+                data.len(),
+                range.end - range.start
+            );
+        }
+        Ok(data)
+    }
+
+    /// URL of a file: the seed URL itself for a single file torrent, otherwise
+    /// the file's path appended to the seed URL as a directory
+    fn file_url(&self, seed_url: &str, file_index: usize) -> String {
+        if self.single_file && !seed_url.ends_with('/') {
+            seed_url.to_string()
+        } else {
+            let separator = if seed_url.ends_with('/') { "" } else { "/" };
+            format!("{}{}{}", seed_url, separator, self.file_paths[file_index])
+        }
+    }
+
+    async fn fetch_range(&self, url: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await?
+            .error_for_status()?;
+        let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        let body = response.bytes().await?;
+        if partial {
+            return Ok(body.to_vec());
+        }
+        // The server ignored the range and sent the whole file
+        body.get(range.start as usize..range.end as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow!("File at {} is shorter than expected", url))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{WebSeed, WebSeedClient, WebSeedKind};
+    use crate::{parse_torrent::multi_file_torrent, serve::serve, stream::test_handle};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn it_fetches_ranges_across_files() {
+        let data = (0..25_u8).collect::<Vec<u8>>();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, test_handle(&data, &[("a", 12), ("b", 13)])));
+
+        let torrent = multi_file_torrent(&data, 10, &[("a", 12), ("b", 13)]);
+        let client = WebSeedClient::new(&torrent, String::new()).unwrap();
+        let seed = WebSeed {
+            url: format!("http://{}/", address),
+            kind: WebSeedKind::UrlList,
+        };
+        assert_eq!(client.fetch(&seed, 1, 0..10).await.unwrap(), &data[10..20]);
+        assert_eq!(client.fetch(&seed, 2, 2..5).await.unwrap(), &data[22..25]);
+
+        let missing = WebSeed {
+            url: format!("http://{}/missing/", address),
+            kind: WebSeedKind::UrlList,
+        };
+        assert!(client.fetch(&missing, 0, 0..10).await.is_err());
+    }
+}